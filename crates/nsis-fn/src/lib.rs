@@ -1,7 +1,7 @@
 use proc_macro::TokenStream;
 use proc_macro2::Span;
-use quote::quote;
-use syn::{parse::Parse, parse_macro_input, Ident, ItemFn};
+use quote::{format_ident, quote};
+use syn::{parse::Parse, parse_macro_input, spanned::Spanned, FnArg, Ident, ItemFn, Type};
 
 struct NsisFn {
     func: ItemFn,
@@ -14,23 +14,65 @@ impl Parse for NsisFn {
     }
 }
 
+/// Returns `Some(true)` for a `&PluginContext` argument, `Some(false)` for a `PluginContext` argument
+/// and `None` for arguments that should be popped from the stack.
+fn context_arg(ty: &Type) -> Option<bool> {
+    let (is_ref, ty) = match ty {
+        Type::Reference(reference) => (true, &*reference.elem),
+        ty => (false, ty),
+    };
+    match ty {
+        Type::Path(path) if path.path.segments.last()?.ident == "PluginContext" => Some(is_ref),
+        _ => None,
+    }
+}
+
 /// Generates a wrapper NSIS compliant dll export that calls `nsis_plugin_api::exdll_init`
-/// automatically. This macro expects the function to return a `Result<(), nsis_plugin_api::Error>`
+/// automatically. This macro expects the function to return a `Result<T, nsis_plugin_api::Error>`
 /// and will automatically push the error to NSIS stack on failure.
+///
+/// Arguments are popped from the NSIS stack in declaration order and converted using
+/// `nsis_plugin_api::FromNsisStack`, and the `Ok` value is pushed onto the NSIS stack
+/// using `nsis_plugin_api::ToNsisStack`. A `PluginContext` or `&PluginContext` argument
+/// isn't popped but receives the `nsis_plugin_api::PluginContext` of the current call.
 #[proc_macro_attribute]
 pub fn nsis_fn(_attr: TokenStream, tokens: TokenStream) -> TokenStream {
     let tokens = parse_macro_input!(tokens as NsisFn);
     let NsisFn { func } = tokens;
 
     let ident = func.sig.ident;
+    let inputs = func.sig.inputs;
+    let output = func.sig.output;
     let block = func.block;
     let attrs = func.attrs;
 
     let new_ident = Ident::new(&format!("__{}", ident), Span::call_site());
 
+    let mut args = Vec::new();
+    let mut pops = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let FnArg::Typed(pat_type) = input else {
+            return syn::Error::new(input.span(), "nsis_fn can't be used on methods")
+                .to_compile_error()
+                .into();
+        };
+        let arg = format_ident!("__arg{}", i);
+        let ty = &pat_type.ty;
+        match context_arg(ty) {
+            Some(true) => args.push(quote!(&context)),
+            Some(false) => args.push(quote!(context)),
+            None => {
+                pops.push(quote! {
+                    let #arg = ::nsis_plugin_api::popvalue::<#ty>()?;
+                });
+                args.push(quote!(#arg));
+            }
+        }
+    }
+
     quote! {
         #[inline(always)]
-        pub unsafe fn #new_ident() -> Result<(), ::nsis_plugin_api::Error> #block
+        pub unsafe fn #new_ident(#inputs) #output #block
 
         #(#attrs)*
         #[no_mangle]
@@ -40,9 +82,16 @@ pub fn nsis_fn(_attr: TokenStream, tokens: TokenStream) -> TokenStream {
             string_size: core::ffi::c_int,
             variables: *mut ::nsis_plugin_api::wchar_t,
             stacktop: *mut *mut ::nsis_plugin_api::stack_t,
+            extra: *mut ::nsis_plugin_api::extra_parameters,
         ) {
             ::nsis_plugin_api::exdll_init(string_size, variables, stacktop);
-            if let Err(e) = #new_ident() {
+            #[allow(unused_variables)]
+            let context = ::nsis_plugin_api::PluginContext::new(hwnd_parent, extra);
+            let result = (|| -> ::core::result::Result<(), ::nsis_plugin_api::Error> {
+                #(#pops)*
+                ::nsis_plugin_api::ToNsisStack::push_to_stack(#new_ident(#(#args),*)?)
+            })();
+            if let Err(e) = result {
                 e.push_err();
             }
         }
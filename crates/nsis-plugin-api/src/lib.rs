@@ -8,9 +8,11 @@ use core::{
     ffi::{c_int, c_void},
     fmt::Display,
     iter,
-    mem::{size_of, size_of_val},
+    mem::{align_of, size_of, size_of_val},
 };
 
+use alloc::borrow::Cow;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::{
@@ -18,14 +20,19 @@ use alloc::{
     vec::Vec,
 };
 
+#[cfg(windows)]
 use windows_sys::Win32::{
     Foundation::GlobalFree,
-    Globalization::{lstrcpyW, lstrcpynW},
+    System::Diagnostics::Debug::{
+        FormatMessageW, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+    },
     System::Memory::{
         GetProcessHeap, GlobalAlloc, HeapAlloc, HeapFree, HeapReAlloc, GPTR, HEAP_ZERO_MEMORY,
     },
 };
 
+use windows_sys::Win32::Foundation::{HMODULE, HWND};
+
 pub use nsis_fn::nsis_fn;
 
 pub type wchar_t = i32;
@@ -37,9 +44,106 @@ pub struct stack_t {
     pub text: [wchar_t; 1],
 }
 
+/// Flags of the running installer, see `exec_flags_t` in NSIS `pluginapi.h`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct exec_flags_t {
+    pub autoclose: c_int,
+    pub all_user_var: c_int,
+    pub exec_error: c_int,
+    pub abort: c_int,
+    pub exec_reboot: c_int,
+    pub reboot_called: c_int,
+    pub XXX_cur_insttype: c_int,
+    pub plugin_api_version: c_int,
+    pub silent: c_int,
+    pub instdir_error: c_int,
+    pub rtl: c_int,
+    pub errlvl: c_int,
+    pub alter_reg_view: c_int,
+    pub status_update: c_int,
+}
+
+/// Messages sent to a callback registered with `RegisterPluginCallback`.
+pub type NSPIM = u32;
+/// The last message a plugin gets, sent when the installer exits.
+pub const NSPIM_UNLOAD: NSPIM = 0;
+/// Sent after `.onGUIEnd`.
+pub const NSPIM_GUIUNLOAD: NSPIM = 1;
+
+pub type NSISPLUGINCALLBACK = Option<unsafe extern "C" fn(NSPIM) -> usize>;
+
+/// The fifth argument NSIS passes to plugin functions, see `extra_parameters` in NSIS `pluginapi.h`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct extra_parameters {
+    pub exec_flags: *mut exec_flags_t,
+    pub ExecuteCodeSegment: Option<unsafe extern "system" fn(c_int, HWND) -> c_int>,
+    pub validate_filename: Option<unsafe extern "system" fn(*mut u16)>,
+    pub RegisterPluginCallback:
+        Option<unsafe extern "system" fn(HMODULE, NSISPLUGINCALLBACK) -> c_int>,
+}
+
 pub static mut G_STRINGSIZE: c_int = 0;
 pub static mut G_VARIABLES: *mut wchar_t = core::ptr::null_mut();
 pub static mut G_STACKTOP: *mut *mut stack_t = core::ptr::null_mut();
+/// The plugin dll, set by the `DllMain` defined in [`nsis_plugin`].
+pub static mut G_HMODULE: HMODULE = core::ptr::null_mut();
+
+/// Hooks registered with [`PluginContext::on_unload`].
+static mut UNLOAD_HOOKS: Vec<fn(NSPIM)> = Vec::new();
+
+/// The callback registered with `RegisterPluginCallback`, forwards messages to [`UNLOAD_HOOKS`].
+unsafe extern "C" fn plugin_callback(msg: NSPIM) -> usize {
+    let hooks = &mut *core::ptr::addr_of_mut!(UNLOAD_HOOKS);
+    for hook in hooks.iter() {
+        hook(msg);
+    }
+    if msg == NSPIM_UNLOAD {
+        hooks.clear();
+    }
+    0
+}
+
+/// Storage for plugin state that is kept between calls, like a cache.
+///
+/// NSIS unloads plugins after each call unless they registered a callback with
+/// [`PluginContext::on_unload`] or were called with `/NOUNLOAD` in NSIS 2,
+/// so the state is only kept in these cases.
+pub struct PluginState<T>(core::cell::UnsafeCell<Option<T>>);
+
+// NSIS calls plugins from a single thread
+unsafe impl<T: Send> Sync for PluginState<T> {}
+
+impl<T> PluginState<T> {
+    pub const fn new() -> Self {
+        Self(core::cell::UnsafeCell::new(None))
+    }
+
+    /// Calls `f` with the state, the state is `None` until it is first set.
+    ///
+    /// # Safety
+    ///
+    /// This function must not be called from another thread or from within `f`.
+    pub unsafe fn with<R>(&self, f: impl FnOnce(&mut Option<T>) -> R) -> R {
+        f(&mut *self.0.get())
+    }
+
+    /// Takes the state out, leaving `None` in its place, to release it in an [`PluginContext::on_unload`] hook.
+    ///
+    /// # Safety
+    ///
+    /// This function must not be called from another thread or from within [`PluginState::with`].
+    pub unsafe fn take(&self) -> Option<T> {
+        self.with(Option::take)
+    }
+}
+
+impl<T> Default for PluginState<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Initis the global variables used by NSIS functions: [`push`], [`pushstr`], [`pushint`], [`pop`], [`popstr`] and [`popint`]
 ///
@@ -60,18 +164,100 @@ pub const NEGATIVE_ONE: &[u16; 3] = &[45, 49, 0];
 #[derive(Debug)]
 pub enum Error {
     StackIsNull,
+    VariablesIsNull,
+    ExtraParametersIsNull,
+    ExecuteCodeSegmentFailed,
+    RegisterPluginCallbackFailed,
     ParseIntError,
+    /// An error specific to a plugin, the message is pushed as is.
+    Custom(String),
+    /// A failed Windows API call, `context` tells what failed, like `OpenProcess 1234`.
+    Win32 {
+        code: u32,
+        context: String,
+    },
 }
 
 impl Error {
-    const fn description(&self) -> &str {
+    /// The Win32 error code, `-1` for errors which aren't [`Error::Win32`].
+    pub fn code(&self) -> i32 {
         match self {
+            Error::Win32 { code, .. } => *code as i32,
+            _ => -1,
+        }
+    }
+
+    fn description(&self) -> Cow<'_, str> {
+        Cow::Borrowed(match self {
             Error::StackIsNull => "Stack is null",
+            Error::VariablesIsNull => "Variables are null",
+            Error::ExtraParametersIsNull => "Extra parameters are null",
+            Error::ExecuteCodeSegmentFailed => "Failed to execute code segment",
+            Error::RegisterPluginCallbackFailed => "Failed to register plugin callback",
             Error::ParseIntError => "Failed to parse integer",
-        }
+            Error::Custom(message) => message,
+            Error::Win32 { code, context } => {
+                return Cow::Owned(format!("{context}: {}", format_message(*code)))
+            }
+        })
     }
+
     pub fn push_err(&self) {
-        let _ = unsafe { pushstr(self.description()) };
+        let _ = unsafe { pushstr(&self.description()) };
+    }
+}
+
+/// The system message of a Win32 error code, like `Access is denied.`
+#[cfg(windows)]
+fn format_message(code: u32) -> String {
+    let mut buffer = [0u16; 512];
+    let length = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            core::ptr::null(),
+            code,
+            0,
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+            core::ptr::null(),
+        )
+    };
+    if length == 0 {
+        return format!("Error {code}");
+    }
+    String::from_utf16_lossy(&buffer[..length as usize])
+        .trim_end()
+        .to_string()
+}
+
+/// Messages of the system aren't available outside of Windows.
+#[cfg(not(windows))]
+fn format_message(code: u32) -> String {
+    format!("Error {code}")
+}
+
+/// Pushes the value of `result`, or its default on error, then the message of `result`, empty on success,
+/// and returns its code to be pushed after them, `0` on success or [`Error::code`] otherwise. This is used by exports
+/// reporting errors as a code and a message, where the code is popped first, then the message and then the value
+/// unless it is `()` which pushes nothing.
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+pub unsafe fn push_error_message<T: ToNsisStack + Default>(
+    result: Result<T, Error>,
+) -> Result<i32, Error> {
+    match result {
+        Ok(value) => {
+            value.push_to_stack()?;
+            pushstr("")?;
+            Ok(0)
+        }
+        Err(error) => {
+            T::default().push_to_stack()?;
+            pushstr(&error.description())?;
+            Ok(error.code())
+        }
     }
 }
 
@@ -85,9 +271,8 @@ pub unsafe fn push(bytes: &[u16]) -> Result<(), Error> {
         return Err(Error::StackIsNull);
     }
 
-    let n = size_of::<stack_t>() + G_STRINGSIZE as usize * 2;
-    let th = GlobalAlloc(GPTR, n) as *mut stack_t;
-    lstrcpyW((*th).text.as_ptr() as _, bytes.as_ptr());
+    let th = alloc_stack_item();
+    copy_wide((*th).text.as_mut_ptr() as _, bytes, G_STRINGSIZE as usize);
     (*th).next = *G_STACKTOP;
     *G_STACKTOP = th;
 
@@ -127,9 +312,10 @@ pub unsafe fn pop() -> Result<Vec<u16>, Error> {
     let mut out = vec![0_u16; G_STRINGSIZE as _];
 
     let th: *mut stack_t = *G_STACKTOP;
-    lstrcpyW(out.as_mut_ptr(), (*th).text.as_ptr() as _);
+    let text = core::slice::from_raw_parts((*th).text.as_ptr() as *const u16, out.len());
+    copy_wide(out.as_mut_ptr(), text, out.len());
     *G_STACKTOP = (*th).next;
-    GlobalFree(th as _);
+    free_stack_item(th);
 
     Ok(out)
 }
@@ -154,6 +340,397 @@ pub unsafe fn popint() -> Result<i32, Error> {
     str.parse().map_err(|_| Error::ParseIntError)
 }
 
+/// The installer state passed to an [`nsis_fn`] export that takes a `PluginContext` or `&PluginContext` argument,
+/// which isn't popped from the stack.
+#[derive(Debug)]
+pub struct PluginContext {
+    hwnd_parent: HWND,
+    extra: *mut extra_parameters,
+}
+
+impl PluginContext {
+    /// # Safety
+    ///
+    /// `extra` must be null or point to the `extra_parameters` NSIS passed to the current plugin call.
+    pub unsafe fn new(hwnd_parent: HWND, extra: *mut extra_parameters) -> Self {
+        Self { hwnd_parent, extra }
+    }
+
+    /// The installer window, null in silent installers.
+    pub fn hwnd_parent(&self) -> HWND {
+        self.hwnd_parent
+    }
+
+    fn extra(&self) -> Result<&extra_parameters, Error> {
+        unsafe { self.extra.as_ref() }.ok_or(Error::ExtraParametersIsNull)
+    }
+
+    fn exec_flags_ptr(&self) -> Result<*mut exec_flags_t, Error> {
+        let exec_flags = self.extra()?.exec_flags;
+        if exec_flags.is_null() {
+            Err(Error::ExtraParametersIsNull)
+        } else {
+            Ok(exec_flags)
+        }
+    }
+
+    /// The flags of the running installer.
+    pub fn exec_flags(&self) -> Result<&exec_flags_t, Error> {
+        self.exec_flags_ptr().map(|flags| unsafe { &*flags })
+    }
+
+    /// Whether the installer is silent, like `IfSilent`.
+    pub fn is_silent(&self) -> bool {
+        self.exec_flags().is_ok_and(|flags| flags.silent != 0)
+    }
+
+    /// Whether the installer closes automatically when done, like `SetAutoClose`.
+    pub fn autoclose(&self) -> bool {
+        self.exec_flags().is_ok_and(|flags| flags.autoclose != 0)
+    }
+
+    /// Whether a reboot is needed, like `IfRebootFlag`.
+    pub fn reboot_flag(&self) -> bool {
+        self.exec_flags().is_ok_and(|flags| flags.exec_reboot != 0)
+    }
+
+    /// Sets the reboot flag, like `SetRebootFlag`.
+    pub fn set_reboot_flag(&self, reboot: bool) -> Result<(), Error> {
+        let flags = self.exec_flags_ptr()?;
+        unsafe { (*flags).exec_reboot = reboot as c_int };
+        Ok(())
+    }
+
+    /// Sets the error flag, like `SetErrors`.
+    pub fn set_error_flag(&self) -> Result<(), Error> {
+        let flags = self.exec_flags_ptr()?;
+        unsafe { (*flags).exec_error = 1 };
+        Ok(())
+    }
+
+    /// Calls a script function, `function_address` comes from `GetFunctionAddress`.
+    ///
+    /// # Safety
+    ///
+    /// The script function can call into plugins so this function should only be called
+    /// when no pointers to the NSIS stack or variables are held.
+    pub unsafe fn execute_code_segment(&self, function_address: i32) -> Result<(), Error> {
+        let execute_code_segment = self
+            .extra()?
+            .ExecuteCodeSegment
+            .ok_or(Error::ExtraParametersIsNull)?;
+
+        // other plugin calls made by the script function override the static variables
+        let (string_size, variables, stacktop) = (G_STRINGSIZE, G_VARIABLES, G_STACKTOP);
+        let result = execute_code_segment(function_address - 1, self.hwnd_parent);
+        exdll_init(string_size, variables, stacktop);
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(Error::ExecuteCodeSegmentFailed)
+        }
+    }
+
+    /// Registers `hook` to be called with [`NSPIM_GUIUNLOAD`] and [`NSPIM_UNLOAD`] when the installer exits.
+    ///
+    /// This also keeps the plugin loaded between calls so [`PluginState`] is kept until then.
+    pub fn on_unload(&self, hook: fn(NSPIM)) -> Result<(), Error> {
+        let register_plugin_callback = self
+            .extra()?
+            .RegisterPluginCallback
+            .ok_or(Error::ExtraParametersIsNull)?;
+
+        // returns 0 on success, 1 if already registered and < 0 on errors
+        if unsafe { register_plugin_callback(G_HMODULE, Some(plugin_callback)) } < 0 {
+            return Err(Error::RegisterPluginCallbackFailed);
+        }
+
+        let hooks = unsafe { &mut *core::ptr::addr_of_mut!(UNLOAD_HOOKS) };
+        if !hooks.iter().any(|h| *h as usize == hook as usize) {
+            hooks.push(hook);
+        }
+
+        Ok(())
+    }
+
+    /// Removes invalid characters from a path, like NSIS does for `$INSTDIR`.
+    pub fn validate_filename(&self, path: &str) -> Result<String, Error> {
+        let validate_filename = self
+            .extra()?
+            .validate_filename
+            .ok_or(Error::ExtraParametersIsNull)?;
+
+        let mut path = encode_utf16(path);
+        unsafe { validate_filename(path.as_mut_ptr()) };
+        Ok(decode_utf16_lossy(&path))
+    }
+}
+
+/// NSIS user variables passed to plugins, in the order of the `variables` block.
+///
+/// `V0` to `V9` are `$0` to `$9` and `R0` to `R9` are `$R0` to `$R9`.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsisVar {
+    V0,
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8,
+    V9,
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9,
+    CmdLine,
+    InstDir,
+    OutDir,
+    ExeDir,
+    Language,
+}
+
+/// Returns a pointer to the first character of an NSIS user variable.
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+unsafe fn uservariable_ptr(var: NsisVar) -> Result<*mut u16, Error> {
+    if G_VARIABLES.is_null() {
+        return Err(Error::VariablesIsNull);
+    }
+
+    Ok((G_VARIABLES as *mut u16).add(var as usize * G_STRINGSIZE as usize))
+}
+
+/// Reads an NSIS user variable, like `$0` or `$INSTDIR`.
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+pub unsafe fn getuservariable(var: NsisVar) -> Result<String, Error> {
+    let ptr = uservariable_ptr(var)?;
+    let bytes = core::slice::from_raw_parts(ptr, G_STRINGSIZE as usize);
+    Ok(decode_utf16_lossy(bytes))
+}
+
+/// Sets an NSIS user variable, like `$0` or `$INSTDIR`.
+/// The value is truncated to [`G_STRINGSIZE`] characters including the nul terminator.
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+pub unsafe fn setuservariable(var: NsisVar, value: &str) -> Result<(), Error> {
+    let ptr = uservariable_ptr(var)?;
+    copy_wide(ptr, &encode_utf16(value), G_STRINGSIZE as usize);
+    Ok(())
+}
+
+/// A value that can be popped from the NSIS stack as an argument of an [`nsis_fn`] export.
+///
+/// Implemented for [`String`], [`i32`], [`u32`], [`u64`], [`bool`], [`Option<T>`] and raw [`Vec<u16>`].
+pub trait FromNsisStack: Sized {
+    /// Converts the raw (nul-terminated) string popped from the NSIS stack.
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error>;
+}
+
+/// A value that can be pushed onto the NSIS stack as the return value of an [`nsis_fn`] export.
+///
+/// Implemented for `()` (pushes nothing), [`String`], [`&str`], [`i32`], [`u32`], [`u64`], [`bool`], [`Option<T>`] and raw [`Vec<u16>`].
+pub trait ToNsisStack {
+    /// Pushes the value onto the NSIS stack.
+    ///
+    /// # Safety
+    ///
+    /// This function reads static variables and should only be called after [`exdll_init`] is called.
+    unsafe fn push_to_stack(self) -> Result<(), Error>;
+}
+
+impl FromNsisStack for String {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        Ok(decode_utf16_lossy(value))
+    }
+}
+
+impl FromNsisStack for i32 {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        decode_utf16_lossy(value)
+            .parse()
+            .map_err(|_| Error::ParseIntError)
+    }
+}
+
+impl FromNsisStack for u32 {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        decode_utf16_lossy(value)
+            .parse()
+            .map_err(|_| Error::ParseIntError)
+    }
+}
+
+impl FromNsisStack for u64 {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        decode_utf16_lossy(value)
+            .parse()
+            .map_err(|_| Error::ParseIntError)
+    }
+}
+
+/// `0` is `false` and any other integer is `true`.
+impl FromNsisStack for bool {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        i32::from_nsis_stack(value).map(|i| i != 0)
+    }
+}
+
+/// An empty string is `None`.
+impl<T: FromNsisStack> FromNsisStack for Option<T> {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        match value.first() {
+            None | Some(0) => Ok(None),
+            Some(_) => T::from_nsis_stack(value).map(Some),
+        }
+    }
+}
+
+impl FromNsisStack for Vec<u16> {
+    fn from_nsis_stack(value: &[u16]) -> Result<Self, Error> {
+        Ok(value.to_vec())
+    }
+}
+
+impl ToNsisStack for () {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ToNsisStack for String {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        pushstr(&self)
+    }
+}
+
+impl ToNsisStack for &str {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        pushstr(self)
+    }
+}
+
+impl ToNsisStack for i32 {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        pushint(self)
+    }
+}
+
+impl ToNsisStack for u32 {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        pushstr(&self.to_string())
+    }
+}
+
+impl ToNsisStack for u64 {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        pushstr(&self.to_string())
+    }
+}
+
+/// `true` is pushed as `1` and `false` as `0`.
+impl ToNsisStack for bool {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        push(if self { ONE } else { ZERO })
+    }
+}
+
+/// `None` is pushed as an empty string.
+impl<T: ToNsisStack> ToNsisStack for Option<T> {
+    unsafe fn push_to_stack(self) -> Result<(), Error> {
+        match self {
+            Some(value) => value.push_to_stack(),
+            None => push(&[0]),
+        }
+    }
+}
+
+/// The bytes are pushed as is, a nul terminator is appended if missing.
+impl ToNsisStack for Vec<u16> {
+    unsafe fn push_to_stack(mut self) -> Result<(), Error> {
+        if !self.contains(&0) {
+            self.push(0);
+        }
+        push(&self)
+    }
+}
+
+/// Pops a value from NSIS stack and converts it using [`FromNsisStack`].
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+pub unsafe fn popvalue<T: FromNsisStack>() -> Result<T, Error> {
+    let bytes = pop()?;
+    T::from_nsis_stack(&bytes)
+}
+
+/// Copies the nul-terminated string in `src` to `dest`, copying at most `max_len` characters
+/// including the nul terminator, like `lstrcpynW`.
+///
+/// # Safety
+///
+/// `dest` must be valid for writes of `max_len` characters.
+unsafe fn copy_wide(dest: *mut u16, src: &[u16], max_len: usize) {
+    if max_len == 0 {
+        return;
+    }
+    let len = src
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(src.len())
+        .min(max_len - 1);
+    core::ptr::copy_nonoverlapping(src.as_ptr(), dest, len);
+    *dest.add(len) = 0;
+}
+
+/// Size in bytes of an item on the NSIS stack, which can hold [`G_STRINGSIZE`] characters.
+unsafe fn stack_item_size() -> usize {
+    size_of::<stack_t>() + G_STRINGSIZE as usize * 2
+}
+
+/// Allocates a zeroed stack item using `GlobalAlloc` as NSIS frees them using `GlobalFree`.
+#[cfg(windows)]
+unsafe fn alloc_stack_item() -> *mut stack_t {
+    GlobalAlloc(GPTR, stack_item_size()) as *mut stack_t
+}
+
+#[cfg(windows)]
+unsafe fn free_stack_item(item: *mut stack_t) {
+    GlobalFree(item as _);
+}
+
+/// Allocates a zeroed stack item using the global allocator, there is no NSIS host
+/// outside of Windows so the stack is only ever owned by a simulated runtime.
+#[cfg(not(windows))]
+unsafe fn alloc_stack_item() -> *mut stack_t {
+    let layout = Layout::from_size_align_unchecked(stack_item_size(), align_of::<stack_t>());
+    alloc::alloc::alloc_zeroed(layout) as *mut stack_t
+}
+
+#[cfg(not(windows))]
+unsafe fn free_stack_item(item: *mut stack_t) {
+    let layout = Layout::from_size_align_unchecked(stack_item_size(), align_of::<stack_t>());
+    alloc::alloc::dealloc(item as *mut u8, layout);
+}
+
 pub fn encode_utf16(str: &str) -> Vec<u16> {
     str.encode_utf16()
         .chain(iter::once(0))
@@ -169,11 +746,15 @@ pub fn decode_utf16_lossy(bytes: &[u16]) -> String {
     String::from_utf16_lossy(bytes)
 }
 
+/// Outside of Windows the plugins are only built for tests, which use the allocator of `std`.
+#[cfg(windows)]
 #[global_allocator]
 static WIN32_ALLOCATOR: Heapalloc = Heapalloc;
 
+#[cfg(windows)]
 pub struct Heapalloc;
 
+#[cfg(windows)]
 unsafe impl GlobalAlloc for Heapalloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         HeapAlloc(GetProcessHeap(), 0, layout.size()) as *mut u8
@@ -198,7 +779,9 @@ unsafe impl GlobalAlloc for Heapalloc {
 }
 
 /// Sets up the needed functions for the NSIS plugin dll,
-/// like `main`, `panic` and `mem*` extern functions
+/// like `main`, `panic` and `mem*` extern functions.
+///
+/// The `mem*` functions are only defined on Windows where the plugins don't link to a C runtime.
 #[macro_export]
 macro_rules! nsis_plugin {
     () => {
@@ -208,6 +791,10 @@ macro_rules! nsis_plugin {
             call_reason: u32,
             _: *mut (),
         ) -> bool {
+            // DLL_PROCESS_ATTACH
+            if call_reason == 1 {
+                unsafe { ::nsis_plugin_api::G_HMODULE = dll_module };
+            }
             true
         }
 
@@ -217,6 +804,7 @@ macro_rules! nsis_plugin {
             unsafe { ::windows_sys::Win32::System::Threading::ExitProcess(u32::MAX) }
         }
 
+        #[cfg(windows)]
         #[no_mangle]
         pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: isize) -> *mut u8 {
             let mut i = 0;
@@ -227,6 +815,7 @@ macro_rules! nsis_plugin {
             return dest;
         }
 
+        #[cfg(windows)]
         #[no_mangle]
         pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: isize) -> i32 {
             let mut i = 0;
@@ -241,6 +830,7 @@ macro_rules! nsis_plugin {
             return 0;
         }
 
+        #[cfg(windows)]
         #[no_mangle]
         pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: isize) -> *mut u8 {
             let mut i = 0;
@@ -252,3 +842,71 @@ macro_rules! nsis_plugin {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from<T: FromNsisStack>(value: &str) -> Result<T, Error> {
+        T::from_nsis_stack(&encode_utf16(value))
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(from::<String>("é ü").unwrap(), "é ü");
+        assert_eq!(from::<String>("").unwrap(), "");
+        // the value ends at the first nul like NSIS strings
+        assert_eq!(
+            String::from_nsis_stack(&[b'a' as u16, 0, b'b' as u16]).unwrap(),
+            "a"
+        );
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(from::<i32>("-12").unwrap(), -12);
+        assert_eq!(from::<u32>("4294967295").unwrap(), u32::MAX);
+        assert_eq!(from::<u64>("18446744073709551615").unwrap(), u64::MAX);
+        for value in ["", "1.5", "0x10", " 1", "abc"] {
+            assert!(
+                matches!(from::<i32>(value), Err(Error::ParseIntError)),
+                "{value}"
+            );
+        }
+        assert!(matches!(from::<u32>("-1"), Err(Error::ParseIntError)));
+        assert!(matches!(
+            from::<i32>("2147483648"),
+            Err(Error::ParseIntError)
+        ));
+    }
+
+    #[test]
+    fn bools() {
+        assert!(!from::<bool>("0").unwrap());
+        assert!(from::<bool>("1").unwrap());
+        assert!(from::<bool>("-1").unwrap());
+        assert!(matches!(from::<bool>("true"), Err(Error::ParseIntError)));
+        assert!(matches!(from::<bool>(""), Err(Error::ParseIntError)));
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(from::<Option<i32>>("").unwrap(), None);
+        assert_eq!(Option::<i32>::from_nsis_stack(&[]).unwrap(), None);
+        assert_eq!(from::<Option<i32>>("5").unwrap(), Some(5));
+        assert_eq!(from::<Option<String>>("").unwrap(), None);
+        assert_eq!(from::<Option<String>>("a").unwrap().as_deref(), Some("a"));
+        // only empty strings are `None`, other invalid values are errors
+        assert!(matches!(
+            from::<Option<i32>>("x"),
+            Err(Error::ParseIntError)
+        ));
+    }
+
+    #[test]
+    fn raw() {
+        let value = [b'a' as u16, 0xD800, 0, b'b' as u16];
+        assert_eq!(Vec::<u16>::from_nsis_stack(&value).unwrap(), value);
+        assert_eq!(Vec::<u16>::from_nsis_stack(&[]).unwrap(), []);
+    }
+}
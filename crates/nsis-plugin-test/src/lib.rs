@@ -0,0 +1,370 @@
+//! A simulated NSIS runtime to exercise plugin exports generated by `#[nsis_fn]`
+//! without building an installer, this works on any host including Linux.
+//!
+//! ```ignore
+//! let mut nsis = Runtime::new();
+//! nsis.call(SemverCompare, &["1.0.0", "1.1.0"]);
+//! assert_eq!(nsis.pop().as_deref(), Some("-1"));
+//! ```
+
+use std::{
+    ffi::c_int,
+    ptr,
+    sync::{Mutex, MutexGuard},
+};
+
+use nsis_plugin_api::{
+    decode_utf16_lossy, exdll_init, exec_flags_t, extra_parameters, getuservariable,
+    setuservariable, stack_t, wchar_t, NsisVar, NSISPLUGINCALLBACK, NSPIM, NSPIM_GUIUNLOAD,
+    NSPIM_UNLOAD,
+};
+use windows_sys::Win32::Foundation::{HMODULE, HWND};
+
+/// The signature of a plugin export generated by `#[nsis_fn]`.
+pub type Export =
+    unsafe extern "C" fn(HWND, c_int, *mut wchar_t, *mut *mut stack_t, *mut extra_parameters);
+
+/// `NSIS_MAX_STRLEN` of the default NSIS build.
+pub const DEFAULT_STRING_SIZE: usize = 1024;
+
+/// Number of user variables NSIS passes to plugins, `$0` to `$LANGUAGE`.
+const VARIABLES_COUNT: usize = NsisVar::Language as usize + 1;
+
+/// `nsis_plugin_api` keeps the runtime in static variables,
+/// so only one [`Runtime`] can be alive at a time.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Function addresses passed to `ExecuteCodeSegment` since the current [`Runtime`] was created.
+static CODE_SEGMENTS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+unsafe extern "system" fn execute_code_segment(code: c_int, _hwnd: HWND) -> c_int {
+    // NSIS plugins pass `GetFunctionAddress` - 1
+    CODE_SEGMENTS.lock().unwrap().push(code + 1);
+    0
+}
+
+/// Leaves the path unchanged.
+unsafe extern "system" fn validate_filename(_path: *mut u16) {}
+
+/// Callbacks registered with `RegisterPluginCallback` since the current [`Runtime`] was created.
+static PLUGIN_CALLBACKS: Mutex<Vec<(usize, unsafe extern "C" fn(NSPIM) -> usize)>> =
+    Mutex::new(Vec::new());
+
+unsafe extern "system" fn register_plugin_callback(
+    module: HMODULE,
+    callback: NSISPLUGINCALLBACK,
+) -> c_int {
+    let Some(callback) = callback else {
+        return -1;
+    };
+    let mut callbacks = PLUGIN_CALLBACKS.lock().unwrap();
+    if callbacks.iter().any(|(m, _)| *m == module as usize) {
+        return 1;
+    }
+    callbacks.push((module as usize, callback));
+    0
+}
+
+/// A fake NSIS runtime owning a stack and a block of user variables.
+///
+/// Only one runtime can exist at a time, creating a second one on the same thread deadlocks.
+pub struct Runtime {
+    string_size: usize,
+    stacktop: Box<*mut stack_t>,
+    variables: Vec<u16>,
+    exec_flags: Box<exec_flags_t>,
+    extra: Box<extra_parameters>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runtime {
+    /// Creates a runtime with an empty stack and empty variables.
+    pub fn new() -> Self {
+        Self::with_string_size(DEFAULT_STRING_SIZE)
+    }
+
+    /// Creates a runtime where strings are limited to `string_size` characters including the nul terminator.
+    pub fn with_string_size(string_size: usize) -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        CODE_SEGMENTS.lock().unwrap().clear();
+        PLUGIN_CALLBACKS.lock().unwrap().clear();
+        let mut exec_flags = Box::<exec_flags_t>::default();
+        let extra = Box::new(extra_parameters {
+            exec_flags: &mut *exec_flags,
+            ExecuteCodeSegment: Some(execute_code_segment),
+            validate_filename: Some(validate_filename),
+            RegisterPluginCallback: Some(register_plugin_callback),
+        });
+        Self {
+            string_size,
+            stacktop: Box::new(ptr::null_mut()),
+            variables: vec![0; string_size * VARIABLES_COUNT],
+            exec_flags,
+            extra,
+            _lock: lock,
+        }
+    }
+
+    /// Points the static variables of `nsis_plugin_api` to this runtime.
+    fn init(&mut self) {
+        unsafe {
+            exdll_init(
+                self.string_size as c_int,
+                self.variables.as_mut_ptr() as *mut wchar_t,
+                &mut *self.stacktop,
+            )
+        };
+    }
+
+    /// Pushes a string onto the stack.
+    pub fn push(&mut self, value: &str) -> &mut Self {
+        self.init();
+        unsafe { nsis_plugin_api::pushstr(value) }.unwrap();
+        self
+    }
+
+    /// Pops a string from the stack, returns `None` if the stack is empty.
+    pub fn pop(&mut self) -> Option<String> {
+        self.init();
+        unsafe { nsis_plugin_api::popstr() }.ok()
+    }
+
+    /// Returns the strings on the stack starting from the top.
+    pub fn stack(&self) -> Vec<String> {
+        let mut items = Vec::new();
+        let mut item = *self.stacktop;
+        while !item.is_null() {
+            let text = unsafe {
+                std::slice::from_raw_parts((*item).text.as_ptr() as *const u16, self.string_size)
+            };
+            items.push(decode_utf16_lossy(text));
+            item = unsafe { (*item).next };
+        }
+        items
+    }
+
+    /// Returns the value of a user variable.
+    pub fn variable(&mut self, var: NsisVar) -> String {
+        self.init();
+        unsafe { getuservariable(var) }.unwrap()
+    }
+
+    /// Sets a user variable, truncating `value` to the string size.
+    pub fn set_variable(&mut self, var: NsisVar, value: &str) -> &mut Self {
+        self.init();
+        unsafe { setuservariable(var, value) }.unwrap();
+        self
+    }
+
+    /// The installer flags passed to plugins, like `silent` for `/S` installers.
+    pub fn exec_flags_mut(&mut self) -> &mut exec_flags_t {
+        &mut self.exec_flags
+    }
+
+    /// The installer flags passed to plugins.
+    pub fn exec_flags(&self) -> &exec_flags_t {
+        &self.exec_flags
+    }
+
+    /// Function addresses of the script functions called by plugins through `ExecuteCodeSegment`.
+    pub fn executed_code_segments(&self) -> Vec<i32> {
+        CODE_SEGMENTS.lock().unwrap().clone()
+    }
+
+    /// Sends [`NSPIM_GUIUNLOAD`] then [`NSPIM_UNLOAD`] to the plugins that registered a callback,
+    /// like NSIS does when the installer exits. This is also done when the runtime is dropped.
+    pub fn unload(&mut self) {
+        let callbacks = std::mem::take(&mut *PLUGIN_CALLBACKS.lock().unwrap());
+        self.init();
+        for msg in [NSPIM_GUIUNLOAD, NSPIM_UNLOAD] {
+            for (_, callback) in &callbacks {
+                unsafe { callback(msg) };
+            }
+        }
+    }
+
+    /// Calls a plugin export like NSIS does for `plugin::Export arg1 arg2`,
+    /// pushing `args` in reverse so `arg1` is on the top of the stack.
+    pub fn call(&mut self, export: Export, args: &[&str]) -> &mut Self {
+        for arg in args.iter().rev() {
+            self.push(arg);
+        }
+        self.init();
+        unsafe {
+            export(
+                ptr::null_mut(),
+                self.string_size as c_int,
+                self.variables.as_mut_ptr() as *mut wchar_t,
+                &mut *self.stacktop,
+                &mut *self.extra,
+            )
+        };
+        self
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.unload();
+        self.init();
+        while unsafe { nsis_plugin_api::pop() }.is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nsis_plugin_api::{nsis_fn, push_error_message, Error, PluginContext, PluginState};
+
+    #[test]
+    fn push_pop() {
+        let mut nsis = Runtime::new();
+        nsis.push("first").push("second");
+        assert_eq!(nsis.stack(), ["second", "first"]);
+        assert_eq!(nsis.pop().as_deref(), Some("second"));
+        assert_eq!(nsis.pop().as_deref(), Some("first"));
+        assert_eq!(nsis.pop(), None);
+    }
+
+    #[test]
+    fn truncates_to_string_size() {
+        let mut nsis = Runtime::with_string_size(4);
+        nsis.push("abcdef").set_variable(NsisVar::V3, "ghijkl");
+        assert_eq!(nsis.pop().as_deref(), Some("abc"));
+        assert_eq!(nsis.variable(NsisVar::V3), "ghi");
+    }
+
+    #[nsis_fn]
+    fn IsSilent(context: &PluginContext) -> Result<bool, Error> {
+        Ok(context.is_silent())
+    }
+
+    #[nsis_fn]
+    fn CallFunction(context: PluginContext, address: i32, reboot: bool) -> Result<(), Error> {
+        context.set_reboot_flag(reboot)?;
+        context.execute_code_segment(address)
+    }
+
+    #[test]
+    fn context() {
+        let mut nsis = Runtime::new();
+        nsis.call(IsSilent, &[]);
+        nsis.exec_flags_mut().silent = 1;
+        nsis.call(IsSilent, &[]);
+        assert_eq!(nsis.stack(), ["1", "0"]);
+
+        nsis.call(CallFunction, &["3", "1"]);
+        assert_eq!(nsis.executed_code_segments(), [3]);
+        assert_eq!(nsis.exec_flags().exec_reboot, 1);
+        assert_eq!(nsis.stack(), ["1", "0"]);
+    }
+
+    static UNLOAD_MESSAGES: Mutex<Vec<NSPIM>> = Mutex::new(Vec::new());
+    static COUNTER: PluginState<i32> = PluginState::new();
+
+    #[nsis_fn]
+    fn Count(context: &PluginContext) -> Result<i32, Error> {
+        context.on_unload(|msg| {
+            UNLOAD_MESSAGES.lock().unwrap().push(msg);
+            unsafe { COUNTER.take() };
+        })?;
+        Ok(COUNTER.with(|count| {
+            let count = count.get_or_insert(0);
+            *count += 1;
+            *count
+        }))
+    }
+
+    #[test]
+    fn unload() {
+        let mut nsis = Runtime::new();
+        nsis.call(Count, &[]).call(Count, &[]);
+        assert_eq!(nsis.stack(), ["2", "1"]);
+        nsis.unload();
+        assert_eq!(
+            *UNLOAD_MESSAGES.lock().unwrap(),
+            [NSPIM_GUIUNLOAD, NSPIM_UNLOAD]
+        );
+        nsis.call(Count, &[]);
+        assert_eq!(nsis.pop().as_deref(), Some("1"));
+    }
+
+    #[nsis_fn]
+    fn Open(code: u32) -> Result<i32, Error> {
+        let result = match code {
+            0 => Ok(()),
+            code => Err(Error::Win32 {
+                code,
+                context: "OpenProcess 1234".into(),
+            }),
+        };
+        push_error_message(result)
+    }
+
+    #[nsis_fn]
+    fn Run(code: u32) -> Result<i32, Error> {
+        let result = match code {
+            0 => Ok(42),
+            code => Err(Error::Win32 {
+                code,
+                context: "CreateProcessW \"app.exe\"".into(),
+            }),
+        };
+        push_error_message(result)
+    }
+
+    #[nsis_fn]
+    fn Fail() -> Result<(), Error> {
+        Err(Error::Win32 {
+            code: 5,
+            context: "TerminateProcess 1234".into(),
+        })
+    }
+
+    #[test]
+    fn errors() {
+        let mut nsis = Runtime::new();
+        nsis.call(Open, &["0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+        assert_eq!(nsis.pop().as_deref(), Some(""));
+
+        // the message of the system is only available on Windows
+        nsis.call(Open, &["87"]);
+        assert_eq!(nsis.pop().as_deref(), Some("87"));
+        assert!(nsis.pop().unwrap().starts_with("OpenProcess 1234: "));
+
+        // the value is pushed before the message, its default on error
+        nsis.call(Run, &["0"]);
+        assert_eq!(nsis.stack(), ["0", "", "42"]);
+        nsis.pop();
+        nsis.pop();
+        nsis.pop();
+        nsis.call(Run, &["2"]);
+        assert_eq!(nsis.pop().as_deref(), Some("2"));
+        assert!(nsis
+            .pop()
+            .unwrap()
+            .starts_with("CreateProcessW \"app.exe\": "));
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+
+        nsis.call(Fail, &[]);
+        assert!(nsis.pop().unwrap().starts_with("TerminateProcess 1234: "));
+        assert_eq!(nsis.pop(), None);
+    }
+
+    #[test]
+    fn variables() {
+        let mut nsis = Runtime::new();
+        nsis.set_variable(NsisVar::R0, "r0")
+            .set_variable(NsisVar::OutDir, "C:\\out");
+        assert_eq!(nsis.variable(NsisVar::R0), "r0");
+        assert_eq!(nsis.variable(NsisVar::OutDir), "C:\\out");
+        assert_eq!(nsis.variable(NsisVar::InstDir), "");
+    }
+}
@@ -1,5 +1,7 @@
 fn main() {
-    if std::env::var("CARGO_FEATURE_TEST").as_deref() != Ok("1") {
+    if std::env::var("CARGO_FEATURE_TEST").as_deref() != Ok("1")
+        && std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc")
+    {
         println!("cargo::rustc-link-arg=/ENTRY:DllMain")
     }
 }
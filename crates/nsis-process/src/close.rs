@@ -0,0 +1,222 @@
+//! Closes processes gracefully before terminating them, behind a trait so the escalation can be tested
+//! with a fake backend on any host.
+
+use alloc::vec::Vec;
+
+pub trait CloseBackend {
+    /// Posts `WM_CLOSE` to the top-level windows of a process, returns `false` if it has none.
+    fn post_close(&mut self, pid: u32) -> bool;
+
+    /// Sends `CTRL_BREAK_EVENT` to the console of a process, returns `false` if it has no console
+    /// or shares it with other processes, see [`owns_console`].
+    fn send_ctrl_break(&mut self, pid: u32) -> bool;
+
+    /// Waits up to `timeout_ms` for all processes to exit, returns the pids still running.
+    fn wait_for_exit(&mut self, pids: &[u32], timeout_ms: u32) -> Vec<u32>;
+
+    /// Terminates a process, returns `false` on error.
+    fn terminate(&mut self, pid: u32) -> bool;
+}
+
+/// Whether `CTRL_BREAK_EVENT` can be sent to all the processes attached to a console, `console_processes`,
+/// to close `pid` without breaking other processes. The `installer` attaches to the console to send it.
+pub fn owns_console(console_processes: &[u32], pid: u32, installer: u32) -> bool {
+    console_processes.contains(&pid)
+        && console_processes
+            .iter()
+            .all(|&process| process == pid || process == installer)
+}
+
+/// The last stage of [`close_processes`], pushed as a number by `CloseProcess`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseStage {
+    /// All processes exited after being asked to close.
+    Closed = 0,
+    /// There was no process to close.
+    NotFound = 1,
+    /// Some processes had to be terminated.
+    Terminated = 2,
+    /// Some processes couldn't be terminated.
+    Failed = 3,
+}
+
+/// Asks processes to close with `WM_CLOSE`, or `CTRL_BREAK_EVENT` for console processes without windows,
+/// waits up to `timeout_ms` for them to exit and terminates the remaining ones.
+///
+/// Processes that can't be asked to close are terminated without waiting, even if `timeout_ms` is `INFINITE`.
+pub fn close_processes(
+    backend: &mut impl CloseBackend,
+    pids: &[u32],
+    timeout_ms: u32,
+) -> CloseStage {
+    if pids.is_empty() {
+        return CloseStage::NotFound;
+    }
+
+    let (asked, mut remaining): (Vec<u32>, Vec<u32>) = pids
+        .iter()
+        .copied()
+        .partition(|&pid| backend.post_close(pid) || backend.send_ctrl_break(pid));
+
+    if !asked.is_empty() {
+        remaining.extend(backend.wait_for_exit(&asked, timeout_ms));
+    }
+
+    if remaining.is_empty() {
+        return CloseStage::Closed;
+    }
+
+    // try to terminate every process even if one fails
+    let failed = remaining
+        .into_iter()
+        .filter(|&pid| !backend.terminate(pid))
+        .count();
+    if failed == 0 {
+        CloseStage::Terminated
+    } else {
+        CloseStage::Failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        PostClose(u32),
+        CtrlBreak(u32),
+        Wait(u32),
+        Terminate(u32),
+    }
+
+    /// Processes with windows and consoles, some of them ignore being asked to close.
+    #[derive(Default)]
+    struct FakeBackend {
+        windows: Vec<u32>,
+        consoles: Vec<u32>,
+        hung: Vec<u32>,
+        protected: Vec<u32>,
+        calls: Vec<Call>,
+    }
+
+    impl CloseBackend for FakeBackend {
+        fn post_close(&mut self, pid: u32) -> bool {
+            self.calls.push(Call::PostClose(pid));
+            self.windows.contains(&pid)
+        }
+
+        fn send_ctrl_break(&mut self, pid: u32) -> bool {
+            self.calls.push(Call::CtrlBreak(pid));
+            self.consoles.contains(&pid)
+        }
+
+        fn wait_for_exit(&mut self, pids: &[u32], timeout_ms: u32) -> Vec<u32> {
+            self.calls.push(Call::Wait(timeout_ms));
+            pids.iter()
+                .copied()
+                .filter(|pid| self.hung.contains(pid))
+                .collect()
+        }
+
+        fn terminate(&mut self, pid: u32) -> bool {
+            self.calls.push(Call::Terminate(pid));
+            !self.protected.contains(&pid)
+        }
+    }
+
+    #[test]
+    fn console_owner() {
+        assert!(owns_console(&[10, 100], 10, 100));
+        assert!(owns_console(&[100, 10], 10, 100));
+        // the shell the process was started from, or another tool in the same console
+        assert!(!owns_console(&[10, 100, 5], 10, 100));
+        assert!(!owns_console(&[5, 100], 10, 100));
+        assert!(!owns_console(&[], 10, 100));
+    }
+
+    #[test]
+    fn not_found() {
+        let mut backend = FakeBackend::default();
+        assert_eq!(
+            close_processes(&mut backend, &[], 1000),
+            CloseStage::NotFound
+        );
+        assert_eq!(backend.calls, []);
+    }
+
+    #[test]
+    fn closed() {
+        let mut backend = FakeBackend {
+            windows: vec![1],
+            consoles: vec![2],
+            ..Default::default()
+        };
+        assert_eq!(
+            close_processes(&mut backend, &[1, 2], 1000),
+            CloseStage::Closed
+        );
+        assert_eq!(
+            backend.calls,
+            [
+                Call::PostClose(1),
+                Call::PostClose(2),
+                Call::CtrlBreak(2),
+                Call::Wait(1000)
+            ]
+        );
+    }
+
+    #[test]
+    fn terminated() {
+        // 1 ignores WM_CLOSE and 3 can't be asked to close
+        let mut backend = FakeBackend {
+            windows: vec![1, 2],
+            hung: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(
+            close_processes(&mut backend, &[1, 2, 3], 500),
+            CloseStage::Terminated
+        );
+        assert_eq!(
+            backend.calls,
+            [
+                Call::PostClose(1),
+                Call::PostClose(2),
+                Call::PostClose(3),
+                Call::CtrlBreak(3),
+                Call::Wait(500),
+                Call::Terminate(3),
+                Call::Terminate(1),
+            ]
+        );
+
+        // nothing to wait for
+        let mut backend = FakeBackend::default();
+        assert_eq!(
+            close_processes(&mut backend, &[3], 500),
+            CloseStage::Terminated
+        );
+        assert!(!backend.calls.contains(&Call::Wait(500)));
+    }
+
+    #[test]
+    fn failed() {
+        let mut backend = FakeBackend {
+            windows: vec![1, 2],
+            hung: vec![1, 2],
+            protected: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(
+            close_processes(&mut backend, &[1, 2], 0),
+            CloseStage::Failed
+        );
+        assert_eq!(
+            backend.calls[backend.calls.len() - 2..],
+            [Call::Terminate(1), Call::Terminate(2)]
+        );
+    }
+}
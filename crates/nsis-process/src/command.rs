@@ -0,0 +1,335 @@
+//! Builds the command line and the environment block of the processes started by `RunAsUser`.
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+use core::iter;
+
+use nsis_plugin_api::Error;
+
+/// The command line of `program` and its `arguments`, the program is quoted so it can contain spaces.
+///
+/// `arguments` are used as is, see [`join_arguments`] to build them from a list.
+pub fn command_line(program: &str, arguments: &str) -> String {
+    let mut command_line = "\"".to_owned() + program + "\"";
+    if !arguments.is_empty() {
+        command_line.push(' ');
+        command_line.push_str(arguments);
+    }
+    command_line
+}
+
+/// Quotes and joins arguments so that `CommandLineToArgvW` and the C runtime parse them back unchanged.
+pub fn join_arguments(arguments: &[String]) -> String {
+    let mut command_line = String::new();
+    for argument in arguments {
+        if !command_line.is_empty() {
+            command_line.push(' ');
+        }
+        quote_argument(argument, &mut command_line);
+    }
+    command_line
+}
+
+/// Quotes an argument if it is empty or contains whitespace or quotes.
+///
+/// Inside quotes, backslashes are literal unless they precede a quote, so backslashes followed by a quote,
+/// including the closing one, are doubled and the quote is escaped.
+fn quote_argument(argument: &str, command_line: &mut String) {
+    if !argument.is_empty() && !argument.contains([' ', '\t', '\n', '\x0b', '"']) {
+        command_line.push_str(argument);
+        return;
+    }
+
+    command_line.push('"');
+    let mut backslashes = 0;
+    for c in argument.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // double the backslashes already pushed and escape the quote
+                command_line.extend(iter::repeat_n('\\', backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        command_line.push(c);
+    }
+    command_line.extend(iter::repeat_n('\\', backslashes));
+    command_line.push('"');
+}
+
+/// Splits a command line into the program and its arguments like `CommandLineToArgvW`.
+///
+/// The program ends at the next quote if it starts with one or at the next whitespace otherwise.
+/// In arguments, whitespace outside quotes separates arguments, `2n` backslashes followed by a quote are `n` backslashes
+/// and a quote starting or ending a quoted part, `2n + 1` backslashes followed by a quote are `n` backslashes
+/// and a literal quote, and `""` inside quotes is a literal quote.
+///
+/// Only used to check [`join_arguments`].
+#[cfg(test)]
+pub fn parse_command_line(command_line: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut chars = command_line.chars().peekable();
+
+    let mut program = String::new();
+    if chars.next_if_eq(&'"').is_some() {
+        program.extend(chars.by_ref().take_while(|&c| c != '"'));
+    } else {
+        while let Some(c) = chars.next_if(|&c| c != ' ' && c != '\t') {
+            program.push(c);
+        }
+    }
+    if command_line.is_empty() {
+        return arguments;
+    }
+    arguments.push(program);
+
+    loop {
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+        if chars.peek().is_none() {
+            return arguments;
+        }
+
+        let mut argument = String::new();
+        let mut in_quotes = false;
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' | '\t' if !in_quotes => break,
+                '\\' => {
+                    let mut backslashes = 1;
+                    while chars.next_if_eq(&'\\').is_some() {
+                        backslashes += 1;
+                    }
+                    if chars.peek() == Some(&'"') {
+                        argument.extend(iter::repeat_n('\\', backslashes / 2));
+                        if backslashes % 2 == 1 {
+                            argument.push('"');
+                            chars.next();
+                        }
+                    } else {
+                        argument.extend(iter::repeat_n('\\', backslashes));
+                    }
+                }
+                '"' if in_quotes => {
+                    if chars.next_if_eq(&'"').is_some() {
+                        argument.push('"');
+                    } else {
+                        in_quotes = false;
+                    }
+                }
+                '"' => in_quotes = true,
+                c => argument.push(c),
+            }
+        }
+        arguments.push(argument);
+    }
+}
+
+/// Splits the `NAME=VALUE` strings of an environment block, which ends with an empty string.
+pub fn parse_environment_block(block: &[u16]) -> Vec<String> {
+    block
+        .split(|c| *c == 0)
+        .take_while(|variable| !variable.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect()
+}
+
+/// The name of a `NAME=VALUE` variable, the name of the hidden per-drive variables like `=C:=C:\` starts with `=`.
+fn variable_name(variable: &str) -> Option<&str> {
+    let end = variable.get(1..)?.find('=')? + 1;
+    Some(&variable[..end])
+}
+
+/// Applies `NAME=VALUE` overrides to the variables of an environment, `NAME=` removes the variable.
+///
+/// Names are case-insensitive like on Windows.
+pub fn merge_environment(
+    mut variables: Vec<String>,
+    overrides: &[String],
+) -> Result<Vec<String>, Error> {
+    for variable in overrides {
+        let name = variable_name(variable)
+            .filter(|name| !name.starts_with('='))
+            .ok_or_else(|| Error::Custom(format!("Invalid environment variable \"{variable}\"")))?;
+
+        variables.retain(|v| !variable_name(v).is_some_and(|n| n.eq_ignore_ascii_case(name)));
+        if variable.len() > name.len() + 1 {
+            variables.push(variable.clone());
+        }
+    }
+    Ok(variables)
+}
+
+/// The environment block for `CreateProcessW` with `CREATE_UNICODE_ENVIRONMENT`, the variables sorted by name.
+pub fn environment_block(mut variables: Vec<String>) -> Vec<u16> {
+    variables
+        .sort_by_cached_key(|variable| variable_name(variable).unwrap_or(variable).to_uppercase());
+
+    let mut block = Vec::new();
+    for variable in &variables {
+        block.extend(variable.encode_utf16());
+        block.push(0);
+    }
+    if variables.is_empty() {
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| (*s).to_owned()).collect()
+    }
+
+    #[test]
+    fn command() {
+        assert_eq!(command_line("cmd", ""), "\"cmd\"");
+        assert_eq!(
+            command_line("C:\\My Dir\\app.exe", "/c timeout 3"),
+            "\"C:\\My Dir\\app.exe\" /c timeout 3"
+        );
+    }
+
+    #[test]
+    fn quote() {
+        for (arguments, command_line) in [
+            (&["a", "b c"][..], "a \"b c\""),
+            (&[""], "\"\""),
+            (&["", ""], "\"\" \"\""),
+            (&["C:\\My Dir\\"], "\"C:\\My Dir\\\\\""),
+            (&["C:\\Dir\\"], "C:\\Dir\\"),
+            (&["a\"b"], "\"a\\\"b\""),
+            (&["a\\\"b"], "\"a\\\\\\\"b\""),
+            (&["a\\b c"], "\"a\\b c\""),
+            (&["tab\there"], "\"tab\there\""),
+            (&["--name=é ü"], "\"--name=é ü\""),
+        ] {
+            let arguments = strings(arguments);
+            assert_eq!(join_arguments(&arguments), command_line);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        for (command_line, arguments) in [
+            ("", &[][..]),
+            ("app.exe", &["app.exe"]),
+            (
+                "\"C:\\My Dir\\app.exe\" a  b",
+                &["C:\\My Dir\\app.exe", "a", "b"],
+            ),
+            ("C:\\app\\\"x.exe a", &["C:\\app\\\"x.exe", "a"]),
+            ("\"app\"x a", &["app", "x", "a"]),
+            ("app \"a b\"c d", &["app", "a bc", "d"]),
+            ("app a\\\\\\b", &["app", "a\\\\\\b"]),
+            ("app a\\\\\"b c\"", &["app", "a\\b c"]),
+            ("app a\\\\\\\"b", &["app", "a\\\"b"]),
+            ("app \"a\"\"b\"", &["app", "a\"b"]),
+            ("app \"\"", &["app", ""]),
+            ("app \"unterminated", &["app", "unterminated"]),
+            ("app\ta\t", &["app", "a"]),
+        ] {
+            assert_eq!(
+                parse_command_line(command_line),
+                arguments,
+                "{command_line}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        // every argument of up to 4 characters made of characters with special meaning
+        let alphabet = ['a', ' ', '\t', '\\', '"'];
+        let mut arguments = vec![String::new()];
+        let mut previous = vec![String::new()];
+        for _ in 0..4 {
+            previous = previous
+                .iter()
+                .flat_map(|argument| {
+                    alphabet.iter().map(move |&c| {
+                        let mut argument = argument.clone();
+                        argument.push(c);
+                        argument
+                    })
+                })
+                .collect();
+            arguments.extend(previous.iter().cloned());
+        }
+
+        let program = "C:\\My Dir\\app.exe";
+        for argument in &arguments {
+            let command_line =
+                command_line(program, &join_arguments(core::slice::from_ref(argument)));
+            assert_eq!(
+                parse_command_line(&command_line),
+                [program, argument],
+                "{command_line}"
+            );
+        }
+
+        // pairs of short arguments, to check they are separated
+        let short = arguments
+            .iter()
+            .filter(|a| a.len() <= 2)
+            .collect::<Vec<_>>();
+        for a in &short {
+            for b in &short {
+                let arguments = [(*a).clone(), (*b).clone()];
+                let command_line = command_line(program, &join_arguments(&arguments));
+                assert_eq!(
+                    parse_command_line(&command_line),
+                    [program, a, b],
+                    "{command_line}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn environment() {
+        let block = "=C:=C:\\Temp\0Path=C:\\Windows\0TEMP=C:\\Temp\0\0"
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        let variables = parse_environment_block(&block);
+        assert_eq!(
+            variables,
+            ["=C:=C:\\Temp", "Path=C:\\Windows", "TEMP=C:\\Temp"]
+        );
+
+        let merged = merge_environment(
+            variables,
+            &strings(&[
+                "PATH=C:\\App;C:\\Windows",
+                "temp=",
+                "APP_MODE=a=b",
+                "UNSET=",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            ["=C:=C:\\Temp", "PATH=C:\\App;C:\\Windows", "APP_MODE=a=b"]
+        );
+
+        assert_eq!(
+            environment_block(merged),
+            "=C:=C:\\Temp\0APP_MODE=a=b\0PATH=C:\\App;C:\\Windows\0\0"
+                .encode_utf16()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(environment_block(Vec::new()), [0, 0]);
+        assert_eq!(parse_environment_block(&[0, 0]), [] as [String; 0]);
+
+        for variable in ["PATH", "=C:=D:\\", "=value", ""] {
+            assert!(
+                merge_environment(vec![], &strings(&[variable])).is_err(),
+                "{variable}"
+            );
+        }
+    }
+}
@@ -2,132 +2,398 @@
 
 extern crate alloc;
 
-use alloc::{borrow::ToOwned, vec, vec::Vec};
-use core::{ffi::c_void, mem, ops::Deref, ops::DerefMut, ptr};
+use alloc::{borrow::ToOwned, format, string::String, vec, vec::Vec};
+use core::{
+    ffi::c_void,
+    mem,
+    ops::Deref,
+    ops::DerefMut,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use nsis_plugin_api::*;
 use windows_sys::{
     w,
     Win32::{
         Foundation::{
-            CloseHandle, GetLastError, ERROR_ELEVATION_REQUIRED, ERROR_INSUFFICIENT_BUFFER, FALSE,
-            HANDLE, TRUE,
+            CloseHandle, GetLastError, BOOL, ERROR_ELEVATION_REQUIRED, ERROR_INSUFFICIENT_BUFFER,
+            ERROR_INVALID_PARAMETER, ERROR_MORE_DATA, ERROR_NOT_FOUND, ERROR_SUCCESS, FALSE,
+            FILETIME, HANDLE, HWND, INVALID_HANDLE_VALUE, LPARAM, TRUE, WAIT_TIMEOUT,
+        },
+        Security::{
+            EqualSid, GetTokenInformation, LookupAccountSidW, TokenUser, PSID, TOKEN_QUERY,
+            TOKEN_USER,
+        },
+        Storage::FileSystem::{
+            FindClose, FindFirstFileW, FindNextFileW, GetLongPathNameW, FILE_ATTRIBUTE_DIRECTORY,
+            FILE_ATTRIBUTE_REPARSE_POINT, WIN32_FIND_DATAW,
         },
-        Security::{EqualSid, GetTokenInformation, TokenUser, TOKEN_QUERY, TOKEN_USER},
         System::{
+            Console::{
+                AttachConsole, FreeConsole, GenerateConsoleCtrlEvent, GetConsoleProcessList,
+                SetConsoleCtrlHandler, CTRL_BREAK_EVENT,
+            },
             Diagnostics::ToolHelp::{
                 CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
                 TH32CS_SNAPPROCESS,
             },
+            Environment::{FreeEnvironmentStringsW, GetEnvironmentStringsW},
+            RestartManager::{
+                RmEndSession, RmForceShutdown, RmGetList, RmRegisterResources, RmRestart,
+                RmShutdown, RmStartSession, CCH_RM_SESSION_KEY, RM_PROCESS_INFO,
+            },
+            SystemInformation::GetTickCount64,
             Threading::{
-                CreateProcessW, GetCurrentProcessId, InitializeProcThreadAttributeList,
-                OpenProcess, OpenProcessToken, TerminateProcess, UpdateProcThreadAttribute,
-                CREATE_NEW_PROCESS_GROUP, CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT,
+                CreateProcessW, GetCurrentProcessId, GetExitCodeProcess, GetProcessTimes,
+                InitializeProcThreadAttributeList, OpenProcess, OpenProcessToken,
+                QueryFullProcessImageNameW, Sleep, TerminateProcess, UpdateProcThreadAttribute,
+                WaitForMultipleObjects, WaitForSingleObject, CREATE_NEW_PROCESS_GROUP,
+                CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, INFINITE,
                 LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_CREATE_PROCESS, PROCESS_INFORMATION,
-                PROCESS_QUERY_INFORMATION, PROCESS_TERMINATE, PROC_THREAD_ATTRIBUTE_PARENT_PROCESS,
-                STARTUPINFOEXW, STARTUPINFOW,
+                PROCESS_NAME_WIN32, PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION,
+                PROCESS_SYNCHRONIZE, PROCESS_TERMINATE, PROC_THREAD_ATTRIBUTE_PARENT_PROCESS,
+                STARTF_USESHOWWINDOW, STARTUPINFOEXW, STARTUPINFOW,
             },
         },
         UI::{
-            Shell::ShellExecuteW,
-            WindowsAndMessaging::{GetShellWindow, GetWindowThreadProcessId, SW_SHOW},
+            Shell::{
+                ShellExecuteExW, SEE_MASK_NOASYNC, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW,
+            },
+            WindowsAndMessaging::{
+                EnumWindows, GetShellWindow, GetWindowThreadProcessId, PostMessageW, SW_SHOW,
+                WM_CLOSE,
+            },
         },
     },
 };
 
+mod close;
+mod command;
+mod pattern;
+mod restart;
+mod system;
+mod tree;
+
+use close::CloseBackend;
+use system::{ProcessEntry, ProcessSystem};
+
 nsis_plugin!();
 
 /// Test if there is a running process with the given name, skipping processes with the host's pid. The input and process names are case-insensitive.
 ///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
 /// # Safety
 ///
-/// This function always expects 1 string on the stack ($1: name) and will panic otherwise.
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
 #[nsis_fn]
-fn FindProcess() -> Result<(), Error> {
-    let name = popstr()?;
-
+fn FindProcess(name: String) -> Result<i32, Error> {
     if !get_processes(&name).is_empty() {
-        push(ZERO)
+        Ok(0)
     } else {
-        push(ONE)
+        Ok(1)
     }
 }
 
 /// Test if there is a running process with the given name that belongs to the current user, skipping processes with the host's pid. The input and process names are case-insensitive.
 ///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
 /// # Safety
 ///
-/// This function always expects 1 string on the stack ($1: name) and will panic otherwise.
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
 #[nsis_fn]
-fn FindProcessCurrentUser() -> Result<(), Error> {
-    let name = popstr()?;
-
+fn FindProcessCurrentUser(name: String) -> Result<i32, Error> {
     let processes = get_processes(&name);
 
     if let Some(user_sid) = get_sid(GetCurrentProcessId()) {
         if processes
             .into_iter()
-            .any(|pid| belongs_to_user(user_sid, pid))
+            .any(|pid| belongs_to_user(user_sid.sid(), pid))
         {
-            push(ZERO)
+            Ok(0)
         } else {
-            push(ONE)
+            Ok(1)
         }
     // Fall back to perMachine checks if we can't get current user id
     } else if processes.is_empty() {
-        push(ONE)
+        Ok(1)
     } else {
-        push(ZERO)
+        Ok(0)
     }
 }
 
-/// Kill all running process with the given name, skipping processes with the host's pid. The input and process names are case-insensitive.
+/// Find the pids of all running processes with the given name, skipping processes with the host's pid. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// Pushes the pids then their count, so the count is popped first followed by `$count` pids.
 ///
 /// # Safety
 ///
-/// This function always expects 1 string on the stack ($1: name) and will panic otherwise.
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
 #[nsis_fn]
-fn KillProcess() -> Result<(), Error> {
-    let name = popstr()?;
+fn FindProcessIds(name: String) -> Result<i32, Error> {
+    let processes = system::find_process_ids(&Win32System, &name);
+    for pid in processes.iter().rev() {
+        pid.push_to_stack()?;
+    }
+    Ok(processes.len() as i32)
+}
 
+/// Get information about the running process with the given pid.
+///
+/// Pushes the executable name, the full path of the executable, the parent pid and the user owning the process
+/// as `DOMAIN\user`, so the executable name is popped first. The path and the user are empty if they can't be read,
+/// which happens for elevated processes or processes of other users when the installer isn't elevated.
+/// Returns an error message if there is no process with this pid.
+///
+/// # Safety
+///
+/// This function always expects 1 integer on the stack ($1: pid) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn GetProcessInfo(pid: u32) -> Result<String, Error> {
+    let info = system::process_info(&Win32System, pid)
+        .ok_or_else(|| Error::Custom(format!("No process with pid {pid}")))?;
+    info.user.push_to_stack()?;
+    info.parent_pid.push_to_stack()?;
+    info.image_path.push_to_stack()?;
+    Ok(info.exe_file)
+}
+
+/// Kill all running process with the given name, skipping processes with the host's pid. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcess(name: String) -> Result<i32, Error> {
     let processes = get_processes(&name);
 
-    if !processes.is_empty() && processes.into_iter().map(kill).all(|b| b) {
-        push(ZERO)
+    if !processes.is_empty() && processes.into_iter().all(kill) {
+        Ok(0)
     } else {
-        push(ONE)
+        Ok(1)
     }
 }
 
-/// Kill all running process with the given name that belong to the current user, skipping processes with the host's pid. The input and process names are case-insensitive.
+/// Test if there is a running process whose executable is the given path or is inside the given directory, like `$INSTDIR`, skipping processes with the host's pid.
+///
+/// Paths are case-insensitive, can use `/` or `\` as separator, `\\?\` long paths or 8.3 short names.
+/// Paths that aren't absolute, with a drive like `C:\` or a share like `\\server\share`, match no process,
+/// so an empty `$INSTDIR` never matches.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: path) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn FindProcessByPath(path: String) -> Result<i32, Error> {
+    if !system::find_process_ids_by_path(&Win32System, &path).is_empty() {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Kill all running process whose executable is the given path or is inside the given directory, like `$INSTDIR`, skipping processes with the host's pid.
+///
+/// Paths are matched like `FindProcessByPath`.
 ///
 /// # Safety
 ///
-/// This function always expects 1 string on the stack ($1: name) and will panic otherwise.
+/// This function always expects 1 string on the stack ($1: path) and pushes an error message instead of running otherwise.
 #[nsis_fn]
-fn KillProcessCurrentUser() -> Result<(), Error> {
-    let name = popstr()?;
+fn KillProcessByPath(path: String) -> Result<i32, Error> {
+    let processes = system::find_process_ids_by_path(&Win32System, &path);
 
+    if !processes.is_empty() && processes.into_iter().all(kill) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Kill all running process with the given name and all their descendants, like WebView2 processes and sidecars,
+/// skipping processes with the host's pid and its descendants. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// Children are killed before their parent. Returns `0` if all processes were killed and `1` otherwise.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessTree(name: String) -> Result<i32, Error> {
+    let order = system::find_tree_kill_order(&Win32System, &name);
+
+    // try to kill every process even if one fails
+    let failed = order.iter().filter(|&&pid| !kill(pid)).count();
+    if !order.is_empty() && failed == 0 {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Kill all running process with the given name that belong to the current user, skipping processes with the host's pid. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessCurrentUser(name: String) -> Result<i32, Error> {
     let processes = get_processes(&name);
 
     if processes.is_empty() {
-        return push(ONE);
+        return Ok(1);
     }
 
-    let success = if let Some(user_sid) = get_sid(GetCurrentProcessId()) {
-        processes
-            .into_iter()
-            .filter(|pid| belongs_to_user(user_sid, *pid))
-            .map(kill)
-            .all(|b| b)
+    if current_user_processes(processes).into_iter().all(kill) {
+        Ok(0)
     } else {
-        processes.into_iter().map(kill).all(|b| b)
-    };
+        Ok(1)
+    }
+}
 
-    if success {
-        push(ZERO)
+/// Kill all running process with the given name, like `KillProcess`, and report why it failed.
+///
+/// Pushes an error code then a message, so the code is popped first. The code is `0` and the message empty if all processes
+/// were killed. Otherwise the code is the Win32 error code of the first failure, like `1168` (`ERROR_NOT_FOUND`) if there is
+/// no process with this name, `5` (`ERROR_ACCESS_DENIED`) if a process can't be killed or `87` (`ERROR_INVALID_PARAMETER`)
+/// if a process exited already, and the message describes it.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessEx(name: String) -> Result<i32, Error> {
+    push_error_message(kill_all(&get_processes(&name), &name))
+}
+
+/// Kill all running process with the given name that belong to the current user, like `KillProcessCurrentUser`,
+/// and report why it failed like `KillProcessEx`.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessCurrentUserEx(name: String) -> Result<i32, Error> {
+    let processes = get_processes(&name);
+    let result = if processes.is_empty() {
+        kill_all(&processes, &name)
     } else {
-        push(ONE)
+        current_user_processes(processes)
+            .into_iter()
+            .map(terminate)
+            .fold(Ok(()), Result::and)
+    };
+    push_error_message(result)
+}
+
+/// Kill all running process whose executable is the given path or is inside the given directory, like `KillProcessByPath`,
+/// and report why it failed like `KillProcessEx`.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: path) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessByPathEx(path: String) -> Result<i32, Error> {
+    let processes = system::find_process_ids_by_path(&Win32System, &path);
+    push_error_message(kill_all(&processes, &path))
+}
+
+/// Kill all running process with the given name and all their descendants, like `KillProcessTree`,
+/// and report why it failed like `KillProcessEx`.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($1: name) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn KillProcessTreeEx(name: String) -> Result<i32, Error> {
+    let order = system::find_tree_kill_order(&Win32System, &name);
+    push_error_message(kill_all(&order, &name))
+}
+
+/// Close all running processes with the given name, skipping processes with the host's pid. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// Processes are asked to close with `WM_CLOSE` posted to their top-level windows, or with `CTRL_BREAK_EVENT` for console
+/// processes without windows that don't share their console with other processes, and are terminated if they are still running after `$2` milliseconds.
+/// A timeout of `4294967295` (`INFINITE`) waits until the processes asked to close exit without terminating them,
+/// processes without windows that can't be sent `CTRL_BREAK_EVENT` are still terminated right away.
+///
+/// Returns `0` if all processes exited after being asked to close, `1` if there is no process with this name,
+/// `2` if some processes had to be terminated and `3` if some processes couldn't be terminated.
+///
+/// # Safety
+///
+/// This function always expects 1 string and 1 integer on the stack ($1: name, $2: timeout in milliseconds) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn CloseProcess(name: String, timeout_ms: u32) -> Result<i32, Error> {
+    let processes = get_processes(&name);
+    Ok(close::close_processes(&mut Win32System, &processes, timeout_ms) as i32)
+}
+
+/// How often the progress callback of `WaitForProcessExit` is called.
+const PROGRESS_INTERVAL_MS: u64 = 250;
+
+/// Wait for all running processes with the given name, or the process with the given pid, to exit,
+/// skipping processes with the host's pid. The input and process names are case-insensitive.
+///
+/// The name can contain `*` and `?` wildcards, and several names can be separated by `|`, like `app.exe|app-worker-*.exe`.
+///
+/// If `$3` is a function address from `GetFunctionAddress`, that function is called about every 250 milliseconds
+/// while waiting with the number of processes still running on the stack, which it must pop.
+///
+/// The processes are opened once before waiting, so a process started later with the pid of one that exited
+/// is never waited for. Processes that can't be opened, like protected ones, are checked about every 250 milliseconds.
+///
+/// Returns `0` if all processes exited, including when there is no matching process, and `1` if some processes
+/// are still running after `$2` milliseconds. A timeout of `4294967295` (`INFINITE`) waits until they all exit.
+///
+/// # Safety
+///
+/// This function always expects 3 values on the stack ($1: name or pid, $2: timeout in milliseconds, $3: callback or empty)
+/// and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn WaitForProcessExit(
+    target: String,
+    timeout_ms: u32,
+    callback: Option<i32>,
+    context: PluginContext,
+) -> Result<i32, Error> {
+    let mut processes = ProcessWaiter::open(&system::find_target_ids(&Win32System, &target));
+    let deadline = GetTickCount64() + u64::from(timeout_ms);
+
+    loop {
+        let remaining = if timeout_ms == INFINITE {
+            u64::from(INFINITE)
+        } else {
+            deadline.saturating_sub(GetTickCount64())
+        };
+        let timeout = match callback {
+            Some(_) => remaining.min(PROGRESS_INTERVAL_MS),
+            None => remaining,
+        };
+        processes.wait(timeout as u32);
+
+        if processes.is_empty() {
+            return Ok(0);
+        }
+        if timeout_ms != INFINITE && GetTickCount64() >= deadline {
+            return Ok(1);
+        }
+        if let Some(callback) = callback {
+            pushint(processes.len() as i32)?;
+            context.execute_code_segment(callback)?;
+        }
     }
 }
 
@@ -137,34 +403,386 @@ fn KillProcessCurrentUser() -> Result<(), Error> {
 /// $1: program
 /// $2: arguments
 #[nsis_fn]
-fn RunAsUser() -> Result<(), Error> {
-    let program = popstr()?;
-    let arguments = popstr()?;
+fn RunAsUser(program: String, arguments: String) -> Result<i32, Error> {
     if run_as_user(&program, &arguments) {
-        push(ZERO)
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Run program as unelevated user, like `RunAsUser`.
+///
+/// `$3` is the working directory of the program, `$OUTDIR` if empty. `$4` is a show window command
+/// like `${SW_SHOWNORMAL}` or `${SW_HIDE}`, the program's default if empty. If `$5` is `1`, waits for the program to exit.
+///
+/// `$6` is the number of `NAME=VALUE` environment variables that follow and override the installer's environment,
+/// `NAME=` removes a variable. Names are case-insensitive. The environment is ignored for programs requiring elevation.
+///
+/// Pushes the exit code of the program if waiting for it and `0` otherwise, then an error code and a message
+/// like `KillProcessEx`, so the error code is popped first, then the message and then the exit code. The error code
+/// is `0` with an empty message if the program was started, the Win32 error code like `2` (`ERROR_FILE_NOT_FOUND`)
+/// if it can't be started and `-1` if an environment variable is invalid.
+///
+/// # Safety
+///
+/// This function always expects 6 values ($1: program, $2: arguments, $3: working directory, $4: show window command,
+/// $5: wait, $6: count) followed by `$6` strings on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn RunAsUserEx(
+    program: String,
+    arguments: String,
+    working_directory: String,
+    show: Option<i32>,
+    wait: bool,
+    count: i32,
+) -> Result<i32, Error> {
+    let overrides = pop_strings(count)?;
+
+    let working_directory = if working_directory.is_empty() {
+        getuservariable(NsisVar::OutDir)?
+    } else {
+        working_directory
+    };
+    let result = run_options(working_directory, &overrides, show, wait).and_then(|options| {
+        run_as_user_with(&program, &arguments, &options).map(|exit_code| exit_code as i32)
+    });
+    push_error_message(result)
+}
+
+/// The options of `RunAsUserEx`, an error if an environment variable is invalid.
+unsafe fn run_options(
+    working_directory: String,
+    overrides: &[String],
+    show: Option<i32>,
+    wait: bool,
+) -> Result<RunOptions, Error> {
+    let environment = if overrides.is_empty() {
+        None
+    } else {
+        let variables = command::merge_environment(current_environment(), overrides)?;
+        Some(command::environment_block(variables))
+    };
+    Ok(RunOptions {
+        working_directory: Some(working_directory).filter(|directory| !directory.is_empty()),
+        environment,
+        show: show.map(|show| show as u16),
+        wait,
+    })
+}
+
+/// Run program as unelevated user, like `RunAsUser`, with a list of arguments which are quoted as needed
+/// so the program receives them unchanged, even with spaces, quotes or trailing backslashes like `C:\My Dir\`.
+///
+/// # Safety
+///
+/// This function always expects 1 string and 1 integer ($1: program, $2: count) followed by `$2` strings
+/// on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn RunAsUserArgs(program: String, count: i32) -> Result<i32, Error> {
+    let arguments = pop_strings(count)?;
+    if run_as_user(&program, &command::join_arguments(&arguments)) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// The Restart Manager session of the last `GetLockingProcesses` call, used by `RestartManagerShutdown`
+/// and `RestartManagerRestart`.
+static RESTART_SESSION: PluginState<RestartSession> = PluginState::new();
+
+/// Find the processes holding files open, using the Restart Manager. Directories are searched recursively.
+///
+/// Pushes the pid, the name and the application type of each process then their count, so the count is popped first
+/// followed by `$count` times the pid, the name and the type. The type is one of `MainWindow`, `OtherWindow`, `Service`,
+/// `Explorer`, `Console`, `Critical` or `Unknown`. Returns an error message if the Restart Manager fails.
+///
+/// The session is kept until the installer exits or `RestartManagerRestart` is called, so these processes can be
+/// closed with `RestartManagerShutdown` and restarted with `RestartManagerRestart`.
+///
+/// # Safety
+///
+/// This function always expects 1 integer ($1: count) followed by `$1` strings on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn GetLockingProcesses(count: i32, context: &PluginContext) -> Result<i32, Error> {
+    let paths = pop_strings(count)?;
+    let files = restart::expand_paths(&paths, list_directory);
+
+    // end the previous session before starting a new one
+    RESTART_SESSION.take();
+    let session = RestartSession::start()?;
+    session.register_files(&files)?;
+    let processes = session.processes()?;
+    RESTART_SESSION.with(|state| *state = Some(session));
+    context.on_unload(|_| {
+        unsafe { RESTART_SESSION.take() };
+    })?;
+
+    for process in processes.iter().rev() {
+        restart::app_type_name(process.ApplicationType).push_to_stack()?;
+        decode_utf16_lossy(&process.strAppName).push_to_stack()?;
+        process.Process.dwProcessId.push_to_stack()?;
+    }
+    Ok(processes.len() as i32)
+}
+
+/// Ask the processes found by the last `GetLockingProcesses` call to close, using the Restart Manager.
+///
+/// If `$1` is `1`, processes which don't close are terminated. Pushes an error code then a message like
+/// `KillProcessEx`, so the code is popped first, `0` with an empty message if all processes were closed.
+///
+/// # Safety
+///
+/// This function always expects 1 integer on the stack ($1: force) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn RestartManagerShutdown(force: bool) -> Result<i32, Error> {
+    let flags = if force { RmForceShutdown as u32 } else { 0 };
+    let result = RESTART_SESSION.with(|session| match session {
+        Some(session) => session.shutdown(flags),
+        None => Err(no_restart_session()),
+    });
+    push_error_message(result)
+}
+
+/// Restart the processes closed by `RestartManagerShutdown` if they registered to be restarted, and end the session.
+///
+/// Pushes an error code then a message like `KillProcessEx`, so the code is popped first,
+/// `0` with an empty message if all processes were restarted.
+///
+/// # Safety
+///
+/// This function doesn't expect anything on the stack.
+#[nsis_fn]
+fn RestartManagerRestart() -> Result<i32, Error> {
+    let result = match RESTART_SESSION.take() {
+        Some(session) => session.restart(),
+        None => Err(no_restart_session()),
+    };
+    push_error_message(result)
+}
+
+fn no_restart_session() -> Error {
+    Error::Custom("No Restart Manager session, GetLockingProcesses must be called first".into())
+}
+
+/// A Restart Manager session, ended when dropped.
+struct RestartSession(u32);
+
+impl RestartSession {
+    fn start() -> Result<Self, Error> {
+        let mut handle = 0;
+        let mut key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+        check_rm(
+            unsafe { RmStartSession(&mut handle, 0, key.as_mut_ptr()) },
+            "RmStartSession",
+        )?;
+        Ok(Self(handle))
+    }
+
+    fn register_files(&self, files: &[String]) -> Result<(), Error> {
+        let files = files
+            .iter()
+            .map(|file| encode_utf16(file))
+            .collect::<Vec<_>>();
+        let pointers = files.iter().map(|file| file.as_ptr()).collect::<Vec<_>>();
+        check_rm(
+            unsafe {
+                RmRegisterResources(
+                    self.0,
+                    pointers.len() as u32,
+                    pointers.as_ptr(),
+                    0,
+                    ptr::null(),
+                    0,
+                    ptr::null(),
+                )
+            },
+            "RmRegisterResources",
+        )
+    }
+
+    fn processes(&self) -> Result<Vec<RM_PROCESS_INFO>, Error> {
+        let mut processes = Vec::new();
+        loop {
+            let mut needed = 0;
+            let mut count = processes.len() as u32;
+            let mut reasons = 0;
+            let result = unsafe {
+                RmGetList(
+                    self.0,
+                    &mut needed,
+                    &mut count,
+                    processes.as_mut_ptr(),
+                    &mut reasons,
+                )
+            };
+            // the list can grow between calls
+            if result == ERROR_MORE_DATA {
+                processes.resize(needed as usize, unsafe { mem::zeroed() });
+                continue;
+            }
+            check_rm(result, "RmGetList")?;
+            processes.truncate(count as usize);
+            return Ok(processes);
+        }
+    }
+
+    fn shutdown(&self, flags: u32) -> Result<(), Error> {
+        check_rm(unsafe { RmShutdown(self.0, flags, None) }, "RmShutdown")
+    }
+
+    fn restart(&self) -> Result<(), Error> {
+        check_rm(unsafe { RmRestart(self.0, 0, None) }, "RmRestart")
+    }
+}
+
+impl Drop for RestartSession {
+    fn drop(&mut self) {
+        unsafe { RmEndSession(self.0) };
+    }
+}
+
+/// Restart Manager functions return their error code instead of setting the last error.
+fn check_rm(code: u32, context: &str) -> Result<(), Error> {
+    if code == ERROR_SUCCESS {
+        Ok(())
     } else {
-        push(ONE)
+        Err(Error::Win32 {
+            code,
+            context: context.to_owned(),
+        })
     }
 }
 
-unsafe fn belongs_to_user(user_sid: *mut c_void, pid: u32) -> bool {
+/// The entries of a directory, skipping links to avoid cycles, or `None` if it isn't a directory.
+fn list_directory(path: &str) -> Option<Vec<restart::DirEntry>> {
+    let pattern = encode_utf16(&format!("{}\\*", path.trim_end_matches(['\\', '/'])));
+    unsafe {
+        let mut data = mem::zeroed::<WIN32_FIND_DATAW>();
+        let find = FindFirstFileW(pattern.as_ptr(), &mut data);
+        if find == INVALID_HANDLE_VALUE {
+            return None;
+        }
+
+        let mut entries = Vec::new();
+        loop {
+            let name = decode_utf16_lossy(&data.cFileName);
+            if name != "."
+                && name != ".."
+                && data.dwFileAttributes & FILE_ATTRIBUTE_REPARSE_POINT == 0
+            {
+                entries.push(restart::DirEntry {
+                    name,
+                    is_directory: data.dwFileAttributes & FILE_ATTRIBUTE_DIRECTORY != 0,
+                });
+            }
+            if FindNextFileW(find, &mut data) == FALSE {
+                break;
+            }
+        }
+        FindClose(find);
+        Some(entries)
+    }
+}
+
+/// Pops the `count` strings following a count.
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+unsafe fn pop_strings(count: i32) -> Result<Vec<String>, Error> {
+    let count =
+        usize::try_from(count).map_err(|_| Error::Custom(format!("Invalid count \"{count}\"")))?;
+    (0..count).map(|_| popstr()).collect()
+}
+
+/// The `NAME=VALUE` variables of the installer's environment.
+unsafe fn current_environment() -> Vec<String> {
+    let environment = GetEnvironmentStringsW();
+    if environment.is_null() {
+        return Vec::new();
+    }
+
+    let mut length = 0;
+    // the block ends with an empty string
+    while *environment.add(length) != 0 || *environment.add(length + 1) != 0 {
+        length += 1;
+    }
+    let variables =
+        command::parse_environment_block(core::slice::from_raw_parts(environment, length + 2));
+    FreeEnvironmentStringsW(environment);
+    variables
+}
+
+unsafe fn belongs_to_user(user_sid: PSID, pid: u32) -> bool {
     let p_sid = get_sid(pid);
     // Trying to get the sid of a process of another user will give us an "Access Denied" error.
     // TODO: Consider checking for HRESULT(0x80070005) if we want to return true for other errors to try and kill those processes later.
     p_sid
-        .map(|p_sid| EqualSid(user_sid, p_sid) != FALSE)
+        .map(|p_sid| EqualSid(user_sid, p_sid.sid()) != FALSE)
         .unwrap_or_default()
 }
 
 fn kill(pid: u32) -> bool {
+    terminate(pid).is_ok()
+}
+
+/// Terminates a process. The error is `ERROR_INVALID_PARAMETER` if it exited already
+/// and `ERROR_ACCESS_DENIED` if it belongs to another user or is elevated.
+fn terminate(pid: u32) -> Result<(), Error> {
     unsafe {
         let handle = OwnedHandle::new(OpenProcess(PROCESS_TERMINATE, 0, pid));
-        TerminateProcess(*handle, 1) == TRUE
+        if handle.is_invalid() {
+            return Err(last_error(|| format!("OpenProcess {pid}")));
+        }
+        if TerminateProcess(*handle, 1) == FALSE {
+            return Err(last_error(|| format!("TerminateProcess {pid}")));
+        }
+        Ok(())
+    }
+}
+
+/// Kills all processes, even if some of them can't be killed, and returns the first error.
+///
+/// Returns `ERROR_NOT_FOUND` if there is no process to kill, `name` is the name or path the processes were found by.
+fn kill_all(processes: &[u32], name: &str) -> Result<(), Error> {
+    if processes.is_empty() {
+        return Err(Error::Win32 {
+            code: ERROR_NOT_FOUND,
+            context: format!("No process matching \"{name}\""),
+        });
+    }
+
+    processes
+        .iter()
+        .map(|&pid| terminate(pid))
+        .fold(Ok(()), Result::and)
+}
+
+/// The processes belonging to the current user, or all of them if the current user can't be found.
+unsafe fn current_user_processes(processes: Vec<u32>) -> Vec<u32> {
+    match get_sid(GetCurrentProcessId()) {
+        Some(user_sid) => processes
+            .into_iter()
+            .filter(|pid| belongs_to_user(user_sid.sid(), *pid))
+            .collect(),
+        // Fall back to perMachine checks if we can't get current user id
+        None => processes,
+    }
+}
+
+/// The `TOKEN_USER` of a process, which owns the SID it points to.
+struct TokenUserBuffer(Vec<u8>);
+
+impl TokenUserBuffer {
+    fn sid(&self) -> PSID {
+        unsafe { (*(self.0.as_ptr() as *const TOKEN_USER)).User.Sid }
     }
 }
 
 // Get the SID of a process. Returns None on error.
-unsafe fn get_sid(pid: u32) -> Option<*mut c_void> {
+unsafe fn get_sid(pid: u32) -> Option<TokenUserBuffer> {
     let handle = OwnedHandle::new(OpenProcess(PROCESS_QUERY_INFORMATION, 0, pid));
     if handle.is_invalid() {
         return None;
@@ -200,66 +818,371 @@ unsafe fn get_sid(pid: u32) -> Option<*mut c_void> {
     {
         None
     } else {
-        Some((*info).User.Sid)
+        Some(TokenUserBuffer(buffer))
     }
 }
 
 fn get_processes(name: &str) -> Vec<u32> {
-    let current_pid = unsafe { GetCurrentProcessId() };
-    let mut processes = Vec::new();
+    system::find_process_ids(&Win32System, name)
+}
 
-    unsafe {
-        let handle = OwnedHandle::new(CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0));
+/// The processes of the running system, read with a toolhelp snapshot.
+struct Win32System;
 
-        let mut process = PROCESSENTRY32W {
-            dwSize: mem::size_of::<PROCESSENTRY32W>() as u32,
-            ..mem::zeroed()
-        };
+impl ProcessSystem for Win32System {
+    fn current_pid(&self) -> u32 {
+        unsafe { GetCurrentProcessId() }
+    }
+
+    fn processes(&self) -> Vec<ProcessEntry> {
+        let mut processes = Vec::new();
 
-        if Process32FirstW(*handle, &mut process) == TRUE {
-            while Process32NextW(*handle, &mut process) == TRUE {
-                if current_pid != process.th32ProcessID
-                    && decode_utf16_lossy(&process.szExeFile).to_lowercase() == name.to_lowercase()
-                {
-                    processes.push(process.th32ProcessID);
+        unsafe {
+            let handle = OwnedHandle::new(CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0));
+
+            let mut process = PROCESSENTRY32W {
+                dwSize: mem::size_of::<PROCESSENTRY32W>() as u32,
+                ..mem::zeroed()
+            };
+
+            if Process32FirstW(*handle, &mut process) == TRUE {
+                while Process32NextW(*handle, &mut process) == TRUE {
+                    processes.push(ProcessEntry {
+                        pid: process.th32ProcessID,
+                        parent_pid: process.th32ParentProcessID,
+                        exe_file: decode_utf16_lossy(&process.szExeFile),
+                    });
                 }
             }
         }
+
+        processes
     }
 
-    processes
+    fn image_path(&self, pid: u32) -> Option<String> {
+        unsafe {
+            let handle = OwnedHandle::new(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid));
+            if handle.is_invalid() {
+                return None;
+            }
+
+            // Long path limit
+            let mut buffer = vec![0u16; 32768];
+            let mut size = buffer.len() as u32;
+            if QueryFullProcessImageNameW(
+                *handle,
+                PROCESS_NAME_WIN32,
+                buffer.as_mut_ptr(),
+                &mut size,
+            ) == FALSE
+            {
+                return None;
+            }
+
+            Some(decode_utf16_lossy(&buffer[..size as usize]))
+        }
+    }
+
+    fn user(&self, pid: u32) -> Option<String> {
+        unsafe {
+            let token_user = get_sid(pid)?;
+
+            let mut name = [0u16; 256];
+            let mut name_length = name.len() as u32;
+            let mut domain = [0u16; 256];
+            let mut domain_length = domain.len() as u32;
+            let mut sid_type = 0;
+            if LookupAccountSidW(
+                ptr::null(),
+                token_user.sid(),
+                name.as_mut_ptr(),
+                &mut name_length,
+                domain.as_mut_ptr(),
+                &mut domain_length,
+                &mut sid_type,
+            ) == FALSE
+            {
+                return None;
+            }
+
+            let name = decode_utf16_lossy(&name[..name_length as usize]);
+            let domain = decode_utf16_lossy(&domain[..domain_length as usize]);
+            Some(if domain.is_empty() {
+                name
+            } else {
+                format!("{domain}\\{name}")
+            })
+        }
+    }
+
+    fn creation_time(&self, pid: u32) -> Option<u64> {
+        unsafe {
+            let handle = OwnedHandle::new(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid));
+            if handle.is_invalid() {
+                return None;
+            }
+
+            let mut creation = mem::zeroed::<FILETIME>();
+            let mut exit = mem::zeroed::<FILETIME>();
+            let mut kernel = mem::zeroed::<FILETIME>();
+            let mut user = mem::zeroed::<FILETIME>();
+            if GetProcessTimes(*handle, &mut creation, &mut exit, &mut kernel, &mut user) == FALSE {
+                return None;
+            }
+
+            Some(u64::from(creation.dwHighDateTime) << 32 | u64::from(creation.dwLowDateTime))
+        }
+    }
+
+    fn long_path(&self, path: &str) -> String {
+        unsafe {
+            let path_wide = encode_utf16(path);
+            let size = GetLongPathNameW(path_wide.as_ptr(), ptr::null_mut(), 0);
+            if size == 0 {
+                return path.to_owned();
+            }
+
+            let mut buffer = vec![0u16; size as usize];
+            let size = GetLongPathNameW(path_wide.as_ptr(), buffer.as_mut_ptr(), size);
+            if size == 0 || size as usize >= buffer.len() {
+                return path.to_owned();
+            }
+
+            decode_utf16_lossy(&buffer[..size as usize])
+        }
+    }
+}
+
+impl CloseBackend for Win32System {
+    fn post_close(&mut self, pid: u32) -> bool {
+        struct Search {
+            pid: u32,
+            found: bool,
+        }
+
+        unsafe extern "system" fn post_close_to_window(hwnd: HWND, lparam: LPARAM) -> BOOL {
+            let search = &mut *(lparam as *mut Search);
+            let mut pid = 0;
+            GetWindowThreadProcessId(hwnd, &mut pid);
+            if pid == search.pid && PostMessageW(hwnd, WM_CLOSE, 0, 0) != FALSE {
+                search.found = true;
+            }
+            TRUE
+        }
+
+        let mut search = Search { pid, found: false };
+        unsafe { EnumWindows(Some(post_close_to_window), &mut search as *mut _ as LPARAM) };
+        search.found
+    }
+
+    fn send_ctrl_break(&mut self, pid: u32) -> bool {
+        unsafe {
+            // The installer receives the events of the console it attaches to, where the default handler would exit it
+            if SetConsoleCtrlHandler(Some(ignore_ctrl_event), TRUE) == FALSE {
+                return false;
+            }
+            if AttachConsole(pid) == FALSE {
+                SetConsoleCtrlHandler(Some(ignore_ctrl_event), FALSE);
+                return false;
+            }
+
+            // CTRL_BREAK_EVENT can only be sent to a process group, and whether the process leads one can't be
+            // checked, or to every process attached to the console, so it is only sent if no other process than
+            // the installer shares the console, like the shell the process was started from
+            let sent = close::owns_console(&console_processes(), pid, GetCurrentProcessId()) && {
+                CTRL_BREAK_RECEIVED.store(false, Ordering::SeqCst);
+                let sent = GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, 0) != FALSE;
+                if sent {
+                    // Handlers are called asynchronously on a new thread, so wait for the installer's
+                    // to be called before detaching
+                    let deadline = GetTickCount64() + CTRL_EVENT_TIMEOUT_MS;
+                    while !CTRL_BREAK_RECEIVED.load(Ordering::SeqCst) && GetTickCount64() < deadline
+                    {
+                        Sleep(10);
+                    }
+                }
+                sent
+            };
+            FreeConsole();
+            SetConsoleCtrlHandler(Some(ignore_ctrl_event), FALSE);
+            sent
+        }
+    }
+
+    fn wait_for_exit(&mut self, pids: &[u32], timeout_ms: u32) -> Vec<u32> {
+        unsafe {
+            let mut processes = ProcessWaiter::open(pids);
+            processes.wait(timeout_ms);
+            processes.running()
+        }
+    }
+
+    fn terminate(&mut self, pid: u32) -> bool {
+        kill(pid)
+    }
+}
+
+/// The pids of the processes attached to the console of the installer, empty on error.
+unsafe fn console_processes() -> Vec<u32> {
+    let mut pids = vec![0u32; 16];
+    loop {
+        let count = GetConsoleProcessList(pids.as_mut_ptr(), pids.len() as u32) as usize;
+        if count <= pids.len() {
+            pids.truncate(count);
+            return pids;
+        }
+        pids.resize(count, 0);
+    }
+}
+
+/// How long `send_ctrl_break` waits for the event to reach the installer's handler.
+const CTRL_EVENT_TIMEOUT_MS: u64 = 1000;
+
+/// Set by [`ignore_ctrl_event`] when the installer receives the `CTRL_BREAK_EVENT` it sent.
+static CTRL_BREAK_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// A console control handler ignoring the events sent to the installer while it is attached to another console.
+unsafe extern "system" fn ignore_ctrl_event(ctrl_type: u32) -> BOOL {
+    if ctrl_type == CTRL_BREAK_EVENT {
+        CTRL_BREAK_RECEIVED.store(true, Ordering::SeqCst);
+    }
+    TRUE
+}
+
+/// `MAXIMUM_WAIT_OBJECTS`, the number of handles `WaitForMultipleObjects` can wait for.
+const MAXIMUM_WAIT_OBJECTS: usize = 64;
+
+/// How often [`ProcessWaiter`] checks the processes it couldn't open.
+const UNOPENED_POLL_INTERVAL_MS: u32 = 250;
+
+/// Processes opened once to wait for them to exit, so a process started later with the pid of one
+/// that exited is never waited for. Handles are closed when the processes exit or when dropped.
+struct ProcessWaiter {
+    handles: Vec<(u32, OwnedHandle)>,
+    /// Processes that can't be opened for another reason than having exited, like protected processes,
+    /// which are still running until opening them tells they exited.
+    unopened: Vec<u32>,
+}
+
+impl ProcessWaiter {
+    unsafe fn open(pids: &[u32]) -> Self {
+        let mut handles = Vec::new();
+        let mut unopened = Vec::new();
+        for &pid in pids {
+            let handle = OwnedHandle::new(OpenProcess(PROCESS_SYNCHRONIZE, FALSE, pid));
+            if !handle.is_invalid() {
+                handles.push((pid, handle));
+            } else if GetLastError() != ERROR_INVALID_PARAMETER {
+                unopened.push(pid);
+            }
+        }
+        Self { handles, unopened }
+    }
+
+    /// The number of processes still running.
+    fn len(&self) -> usize {
+        self.handles.len() + self.unopened.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pids of the processes still running.
+    fn running(&self) -> Vec<u32> {
+        let mut running = self.unopened.clone();
+        running.extend(self.handles.iter().map(|(pid, _)| *pid));
+        running
+    }
+
+    /// Waits up to `timeout_ms` for all processes to exit, `INFINITE` waits until they do.
+    unsafe fn wait(&mut self, timeout_ms: u32) {
+        let deadline = GetTickCount64() + u64::from(timeout_ms);
+        let remaining = || {
+            if timeout_ms == INFINITE {
+                INFINITE
+            } else {
+                deadline.saturating_sub(GetTickCount64()) as u32
+            }
+        };
+
+        if self.handles.is_empty() {
+            while !self.unopened.is_empty() && remaining() > 0 {
+                Sleep(remaining().min(UNOPENED_POLL_INTERVAL_MS));
+                self.unopened.retain(|&pid| !has_exited(pid));
+            }
+            return;
+        }
+
+        for chunk in self.handles.chunks(MAXIMUM_WAIT_OBJECTS) {
+            let chunk = chunk.iter().map(|(_, handle)| **handle).collect::<Vec<_>>();
+            WaitForMultipleObjects(chunk.len() as u32, chunk.as_ptr(), TRUE, remaining());
+        }
+
+        self.handles
+            .retain(|(_, handle)| WaitForSingleObject(**handle, 0) == WAIT_TIMEOUT);
+        self.unopened.retain(|&pid| !has_exited(pid));
+    }
+}
+
+/// Whether there is no process with this pid anymore, which `OpenProcess` reports with `ERROR_INVALID_PARAMETER`.
+unsafe fn has_exited(pid: u32) -> bool {
+    let handle = OwnedHandle::new(OpenProcess(PROCESS_SYNCHRONIZE, FALSE, pid));
+    handle.is_invalid() && GetLastError() == ERROR_INVALID_PARAMETER
+}
+
+/// How [`run_as_user_with`] starts a program.
+#[derive(Default)]
+struct RunOptions {
+    /// The current directory of the program, the installer's one if `None`.
+    working_directory: Option<String>,
+    /// A block built by [`command::environment_block`], the installer's environment if `None`.
+    environment: Option<Vec<u16>>,
+    /// A `SW_*` show window command.
+    show: Option<u16>,
+    /// Whether to wait for the program to exit.
+    wait: bool,
 }
 
 /// Return true if success
+unsafe fn run_as_user(program: &str, arguments: &str) -> bool {
+    run_as_user_with(program, arguments, &RunOptions::default()).is_ok()
+}
+
+/// Returns the exit code of the program if waiting for it and `0` otherwise.
 ///
 /// Ported from https://devblogs.microsoft.com/oldnewthing/20190425-00/?p=102443
-unsafe fn run_as_user(program: &str, arguments: &str) -> bool {
+unsafe fn run_as_user_with(
+    program: &str,
+    arguments: &str,
+    options: &RunOptions,
+) -> Result<u32, Error> {
     let hwnd = GetShellWindow();
     if hwnd.is_null() {
-        return false;
+        return Err(Error::Custom("No shell window".into()));
     }
 
     let mut proccess_id = 0;
     if GetWindowThreadProcessId(hwnd, &mut proccess_id) == FALSE as u32 {
-        return false;
+        return Err(last_error(|| "GetWindowThreadProcessId".into()));
     }
 
     let process = OwnedHandle::new(OpenProcess(PROCESS_CREATE_PROCESS, FALSE, proccess_id));
     if process.is_invalid() {
-        return false;
+        return Err(last_error(|| format!("OpenProcess {proccess_id}")));
     }
 
     let mut size = 0;
     if !(InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut size) == FALSE
         && GetLastError() == ERROR_INSUFFICIENT_BUFFER)
     {
-        return false;
+        return Err(last_error(|| "InitializeProcThreadAttributeList".into()));
     }
 
     let mut buffer = vec![0u8; size];
     let attribute_list = buffer.as_mut_ptr() as LPPROC_THREAD_ATTRIBUTE_LIST;
     if InitializeProcThreadAttributeList(attribute_list, 1, 0, &mut size) == FALSE {
-        return false;
+        return Err(last_error(|| "InitializeProcThreadAttributeList".into()));
     }
 
     if UpdateProcThreadAttribute(
@@ -272,24 +1195,34 @@ unsafe fn run_as_user(program: &str, arguments: &str) -> bool {
         ptr::null(),
     ) == FALSE
     {
-        return false;
+        return Err(last_error(|| "UpdateProcThreadAttribute".into()));
     }
 
-    let startup_info = STARTUPINFOEXW {
+    let mut startup_info = STARTUPINFOEXW {
         StartupInfo: STARTUPINFOW {
             cb: mem::size_of::<STARTUPINFOEXW>() as _,
             ..mem::zeroed()
         },
         lpAttributeList: attribute_list,
     };
-    let mut process_info: PROCESS_INFORMATION = mem::zeroed();
-    let mut command_line = "\"".to_owned() + program + "\"";
-    if !arguments.is_empty() {
-        command_line.push(' ');
-        command_line.push_str(arguments);
+    if let Some(show) = options.show {
+        startup_info.StartupInfo.dwFlags |= STARTF_USESHOWWINDOW;
+        startup_info.StartupInfo.wShowWindow = show;
     }
+    let mut process_info: PROCESS_INFORMATION = mem::zeroed();
+    let command_line = command::command_line(program, arguments);
 
     let program_wide = encode_utf16(program);
+    let working_directory = options.working_directory.as_deref().map(encode_utf16);
+    let working_directory = working_directory
+        .as_ref()
+        .map_or(ptr::null(), |directory| directory.as_ptr());
+    let environment = options
+        .environment
+        .as_ref()
+        .map_or(ptr::null(), |environment| {
+            environment.as_ptr() as *const c_void
+        });
 
     if CreateProcessW(
         program_wide.as_ptr(),
@@ -298,30 +1231,59 @@ unsafe fn run_as_user(program: &str, arguments: &str) -> bool {
         ptr::null(),
         FALSE,
         CREATE_UNICODE_ENVIRONMENT | CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT,
-        ptr::null(),
-        ptr::null(),
+        environment,
+        working_directory,
         &startup_info as *const _ as _,
         &mut process_info,
     ) != FALSE
     {
-        CloseHandle(process_info.hProcess);
+        let process = OwnedHandle::new(process_info.hProcess);
         CloseHandle(process_info.hThread);
-        true
+        Ok(exit_code(&process, options.wait))
     } else if GetLastError() == ERROR_ELEVATION_REQUIRED {
-        let result = ShellExecuteW(
-            ptr::null_mut(),
-            w!("open"),
-            program_wide.as_ptr(),
-            encode_utf16(&command_line).as_ptr(),
-            ptr::null(),
-            SW_SHOW,
-        );
-        result as isize > 32
+        // ShellExecuteExW builds the command line from the file and the parameters, so they are only the arguments,
+        // the environment can't be passed to it
+        let arguments = encode_utf16(arguments);
+        let mut info = SHELLEXECUTEINFOW {
+            cbSize: mem::size_of::<SHELLEXECUTEINFOW>() as _,
+            fMask: SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC,
+            lpVerb: w!("open"),
+            lpFile: program_wide.as_ptr(),
+            lpParameters: arguments.as_ptr(),
+            lpDirectory: working_directory,
+            nShow: options.show.map_or(SW_SHOW, i32::from),
+            ..mem::zeroed()
+        };
+        if ShellExecuteExW(&mut info) == FALSE {
+            return Err(last_error(|| format!("ShellExecuteExW \"{program}\"")));
+        }
+        // No process is started when the program is opened by a running process through DDE
+        let process = OwnedHandle::new(info.hProcess);
+        Ok(exit_code(&process, options.wait && !process.is_invalid()))
     } else {
-        false
+        Err(last_error(|| format!("CreateProcessW \"{program}\"")))
+    }
+}
+
+/// A [`Error::Win32`] with the code of `GetLastError`, which is read before `context` is called.
+unsafe fn last_error(context: impl FnOnce() -> String) -> Error {
+    let code = GetLastError();
+    Error::Win32 {
+        code,
+        context: context(),
     }
 }
 
+/// The exit code of a process after waiting for it to exit if `wait` is true, `0` otherwise.
+unsafe fn exit_code(process: &OwnedHandle, wait: bool) -> u32 {
+    let mut exit_code = 0;
+    if wait {
+        WaitForSingleObject(**process, INFINITE);
+        GetExitCodeProcess(**process, &mut exit_code);
+    }
+    exit_code
+}
+
 struct OwnedHandle(HANDLE);
 
 impl OwnedHandle {
@@ -358,30 +1320,44 @@ impl DerefMut for OwnedHandle {
 
 #[cfg(test)]
 mod tests {
-
+    #[cfg(windows)]
     use super::*;
 
     #[test]
+    #[cfg(windows)]
     fn find_process() {
         let processes = get_processes("explorer.exe");
         assert!(!processes.is_empty());
     }
 
     #[test]
+    #[cfg(windows)]
     fn kill_process() {
         let processes = get_processes("something_that_doesnt_exist.exe");
         // TODO: maybe find some way to spawn a dummy process we can kill here?
         // This will return true on empty iterators so it's basically no-op right now
-        assert!(processes.into_iter().map(kill).all(|b| b));
+        assert!(processes.into_iter().all(kill));
     }
 
     #[test]
+    #[cfg(windows)]
     fn spawn_cmd() {
         unsafe { run_as_user("cmd", "/c timeout 3") };
     }
 
     #[test]
-    #[cfg(feature = "test")]
+    #[cfg(windows)]
+    fn spawn_missing() {
+        let result =
+            unsafe { run_as_user_with("C:\\does\\not\\exist.exe", "", &RunOptions::default()) };
+        assert!(matches!(
+            result,
+            Err(Error::Win32 { code: 2 | 3, ref context }) if context.starts_with("CreateProcessW")
+        ));
+    }
+
+    #[test]
+    #[cfg(all(windows, feature = "test"))]
     fn spawn_with_spaces() {
         extern crate std;
         use alloc::format;
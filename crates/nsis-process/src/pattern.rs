@@ -0,0 +1,116 @@
+//! Process name patterns, like `app.exe|app-helper.exe|app-worker-*.exe`.
+
+use alloc::vec::Vec;
+
+/// A `|`-separated list of case-insensitive names, where `*` matches any number of characters and `?` matches one.
+#[derive(Debug)]
+pub struct NamePattern {
+    alternatives: Vec<Vec<char>>,
+}
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Self {
+        let alternatives = pattern
+            .split('|')
+            .map(str::trim)
+            .filter(|alternative| !alternative.is_empty())
+            .map(|alternative| alternative.to_lowercase().chars().collect())
+            .collect();
+        Self { alternatives }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase().chars().collect::<Vec<_>>();
+        self.alternatives
+            .iter()
+            .any(|alternative| wildcard_match(alternative, &name))
+    }
+}
+
+/// Matches `name` against a pattern with `*` and `?` wildcards.
+///
+/// On a mismatch, retries from the last `*` with one more character consumed by it,
+/// which is enough since a later `*` can consume anything an earlier one could.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut last_star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match last_star {
+                Some((star, star_n)) => {
+                    p = star + 1;
+                    n = star_n + 1;
+                    last_star = Some((star, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        for (pattern, name, matches) in [
+            ("app.exe", "app.exe", true),
+            ("app.exe", "APP.EXE", true),
+            ("App.exe", "app.exe", true),
+            ("app.exe", "app.exe.bak", false),
+            ("app.exe", "myapp.exe", false),
+            ("app-worker-*.exe", "app-worker-1.exe", true),
+            ("app-worker-*.exe", "app-worker-.exe", true),
+            ("app-worker-*.exe", "app-worker-gpu-12.exe", true),
+            ("app-worker-*.exe", "app-worker-1.exe.exe", true),
+            ("app-worker-*.exe", "app-worker-1.dll", false),
+            ("app?.exe", "app2.exe", true),
+            ("app?.exe", "app.exe", false),
+            ("app?.exe", "app12.exe", false),
+            ("*", "anything.exe", true),
+            ("*", "", true),
+            ("*.exe", "app.exe", true),
+            ("*.exe", "app.dll", false),
+            ("a*b*c.exe", "aXbYbZc.exe", true),
+            ("a*b*c.exe", "aXcYb.exe", false),
+            ("**app**", "myapp.exe", true),
+            ("", "app.exe", false),
+        ] {
+            assert_eq!(
+                NamePattern::new(pattern).matches(name),
+                matches,
+                "({pattern}, {name})"
+            );
+        }
+    }
+
+    #[test]
+    fn alternatives() {
+        let pattern = NamePattern::new("app.exe|app-helper.exe| app-updater.exe |app-worker-*.exe");
+        for name in [
+            "app.exe",
+            "app-helper.exe",
+            "app-updater.exe",
+            "app-worker-3.exe",
+        ] {
+            assert!(pattern.matches(name), "{name}");
+        }
+        for name in ["app-helper2.exe", "helper.exe", "app-worker.exe"] {
+            assert!(!pattern.matches(name), "{name}");
+        }
+
+        assert!(!NamePattern::new("|").matches(""));
+    }
+}
@@ -0,0 +1,123 @@
+//! Resources registered with the Restart Manager by `GetLockingProcesses`, which only accepts files,
+//! so directories are expanded to the files they contain.
+
+use alloc::{format, string::String, vec::Vec};
+
+/// An entry of a directory listing, without `.` and `..`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_directory: bool,
+}
+
+/// The files of `paths`, where directories are replaced by all the files they contain recursively.
+///
+/// `list_directory` returns `None` if a path isn't a directory, so the given paths that can't be listed are kept
+/// as files, while subdirectories that can't be listed are skipped.
+pub fn expand_paths(
+    paths: &[String],
+    mut list_directory: impl FnMut(&str) -> Option<Vec<DirEntry>>,
+) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in paths {
+        match list_directory(path) {
+            Some(entries) => expand_entries(path, entries, &mut list_directory, &mut files),
+            None => files.push(path.clone()),
+        }
+    }
+    files
+}
+
+fn expand_entries(
+    directory: &str,
+    entries: Vec<DirEntry>,
+    list_directory: &mut impl FnMut(&str) -> Option<Vec<DirEntry>>,
+    files: &mut Vec<String>,
+) {
+    for entry in entries {
+        let path = join_path(directory, &entry.name);
+        if !entry.is_directory {
+            files.push(path);
+        } else if let Some(entries) = list_directory(&path) {
+            expand_entries(&path, entries, list_directory, files);
+        }
+    }
+}
+
+fn join_path(directory: &str, name: &str) -> String {
+    format!("{}\\{name}", directory.trim_end_matches(['\\', '/']))
+}
+
+/// The name of an `RM_APP_TYPE`, pushed by `GetLockingProcesses`.
+pub fn app_type_name(app_type: i32) -> &'static str {
+    match app_type {
+        1 => "MainWindow",
+        2 => "OtherWindow",
+        3 => "Service",
+        4 => "Explorer",
+        5 => "Console",
+        1000 => "Critical",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{borrow::ToOwned, vec};
+
+    fn entry(name: &str, is_directory: bool) -> DirEntry {
+        DirEntry {
+            name: name.to_owned(),
+            is_directory,
+        }
+    }
+
+    #[test]
+    fn expand() {
+        let list_directory = |path: &str| match path {
+            "C:\\App" | "C:\\App\\" => Some(vec![
+                entry("app.exe", false),
+                entry("resources", true),
+                entry("locked", true),
+                entry("app.dll", false),
+            ]),
+            "C:\\App\\resources" => Some(vec![entry("icon.ico", false), entry("empty", true)]),
+            "C:\\App\\resources\\empty" => Some(vec![]),
+            _ => None,
+        };
+
+        let files = [
+            "C:\\App\\app.exe",
+            "C:\\App\\resources\\icon.ico",
+            "C:\\App\\app.dll",
+        ];
+        assert_eq!(expand_paths(&["C:\\App".to_owned()], list_directory), files);
+        assert_eq!(
+            expand_paths(&["C:\\App\\".to_owned()], list_directory),
+            files
+        );
+        // files and paths that don't exist are registered as is
+        assert_eq!(
+            expand_paths(
+                &["C:\\App\\app.exe".to_owned(), "C:\\Missing".to_owned()],
+                list_directory
+            ),
+            ["C:\\App\\app.exe", "C:\\Missing"]
+        );
+        assert_eq!(
+            expand_paths(&["C:\\App\\resources\\empty".to_owned()], list_directory),
+            [] as [String; 0]
+        );
+    }
+
+    #[test]
+    fn app_types() {
+        assert_eq!(app_type_name(0), "Unknown");
+        assert_eq!(app_type_name(1), "MainWindow");
+        assert_eq!(app_type_name(3), "Service");
+        assert_eq!(app_type_name(5), "Console");
+        assert_eq!(app_type_name(1000), "Critical");
+        assert_eq!(app_type_name(42), "Unknown");
+    }
+}
@@ -0,0 +1,412 @@
+//! The process table of the system behind a trait, so the logic selecting processes
+//! can be tested with a fake process table on any host.
+
+use alloc::{format, string::String, vec, vec::Vec};
+
+use super::{pattern::NamePattern, tree::kill_order};
+
+/// A process of a toolhelp snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessEntry {
+    pub pid: u32,
+    pub parent_pid: u32,
+    /// The executable name, like `explorer.exe`.
+    pub exe_file: String,
+}
+
+pub trait ProcessSystem {
+    /// The pid of the installer, which is never matched.
+    fn current_pid(&self) -> u32;
+
+    /// A snapshot of the running processes.
+    fn processes(&self) -> Vec<ProcessEntry>;
+
+    /// The full path of the executable of a process.
+    fn image_path(&self, pid: u32) -> Option<String>;
+
+    /// The user owning a process, as `DOMAIN\user`.
+    fn user(&self, pid: u32) -> Option<String>;
+
+    /// The creation time of a process as a `FILETIME`, to tell whether it is newer than a pid that was reused.
+    fn creation_time(&self, pid: u32) -> Option<u64>;
+
+    /// Expands the 8.3 short names of a path, like `C:\PROGRA~1`, the path is unchanged if it doesn't exist.
+    fn long_path(&self, path: &str) -> String;
+}
+
+/// What `GetProcessInfo` pushes about a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub exe_file: String,
+    pub image_path: Option<String>,
+    pub parent_pid: u32,
+    pub user: Option<String>,
+}
+
+/// The pids of the processes whose name matches the [`NamePattern`] `name`, skipping the installer.
+pub fn find_process_ids(system: &impl ProcessSystem, name: &str) -> Vec<u32> {
+    matching_ids(&system.processes(), system.current_pid(), name)
+}
+
+fn matching_ids(processes: &[ProcessEntry], current_pid: u32, name: &str) -> Vec<u32> {
+    let pattern = NamePattern::new(name);
+    processes
+        .iter()
+        .filter(|process| process.pid != current_pid && pattern.matches(&process.exe_file))
+        .map(|process| process.pid)
+        .collect()
+}
+
+/// The pids to terminate to kill the processes matching the [`NamePattern`] `name` and their descendants,
+/// children before their parent, see [`kill_order`]. Empty if no process matches.
+///
+/// The matching processes and the tree are read from the same snapshot.
+pub fn find_tree_kill_order(system: &impl ProcessSystem, name: &str) -> Vec<u32> {
+    let processes = system.processes();
+    let current_pid = system.current_pid();
+    let roots = matching_ids(&processes, current_pid, name);
+    kill_order(&processes, &roots, current_pid, |pid| {
+        system.creation_time(pid)
+    })
+}
+
+/// The pids of the processes matching `target`, a pid or a [`NamePattern`], skipping the installer.
+pub fn find_target_ids(system: &impl ProcessSystem, target: &str) -> Vec<u32> {
+    let Ok(pid) = target.parse::<u32>() else {
+        return find_process_ids(system, target);
+    };
+
+    let exists = pid != system.current_pid()
+        && system
+            .processes()
+            .into_iter()
+            .any(|process| process.pid == pid);
+    if exists {
+        vec![pid]
+    } else {
+        Vec::new()
+    }
+}
+
+/// The pids of the processes whose executable is `path` or is inside the directory `path`, skipping the installer.
+///
+/// Paths are compared with [`normalize_path`] after expanding short names,
+/// processes whose path can't be read never match. A `path` that isn't absolute matches nothing.
+pub fn find_process_ids_by_path(system: &impl ProcessSystem, path: &str) -> Vec<u32> {
+    let current_pid = system.current_pid();
+    let path = normalize_path(&system.long_path(path));
+    if !is_absolute(&path) {
+        return Vec::new();
+    }
+    system
+        .processes()
+        .into_iter()
+        .filter(|process| process.pid != current_pid)
+        .filter(|process| {
+            system.image_path(process.pid).is_some_and(|image_path| {
+                is_same_or_inside(&normalize_path(&system.long_path(&image_path)), &path)
+            })
+        })
+        .map(|process| process.pid)
+        .collect()
+}
+
+/// Normalizes a Windows path so equal paths compare equal: removes the `\\?\` prefix, uses `\` as separator,
+/// resolves `.` and `..`, removes trailing separators and lowercases it.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.replace('/', "\\");
+    let path = if let Some(unc) = path.strip_prefix("\\\\?\\UNC\\") {
+        format!("\\\\{unc}")
+    } else if let Some(path) = path.strip_prefix("\\\\?\\") {
+        path.into()
+    } else {
+        path
+    };
+    let (prefix, path) = match path.strip_prefix("\\\\") {
+        Some(path) => ("\\\\", path),
+        None => ("", path.as_str()),
+    };
+
+    let mut segments = Vec::new();
+    for segment in path.split('\\') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    format!("{prefix}{}", segments.join("\\")).to_lowercase()
+}
+
+/// Whether the normalized `path` starts with a drive, like `c:`, or a share, like `\\server\share`.
+fn is_absolute(path: &str) -> bool {
+    if let Some(unc) = path.strip_prefix("\\\\") {
+        let mut segments = unc.split('\\');
+        return segments.next().is_some_and(|server| !server.is_empty())
+            && segments.next().is_some_and(|share| !share.is_empty());
+    }
+    let mut chars = path.chars();
+    chars
+        .next()
+        .is_some_and(|drive| drive.is_ascii_alphabetic())
+        && chars.next() == Some(':')
+        && chars.next().is_none_or(|separator| separator == '\\')
+}
+
+/// Whether the normalized `path` is `parent` or is inside the directory `parent`.
+fn is_same_or_inside(path: &str, parent: &str) -> bool {
+    path.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('\\'))
+}
+
+/// Describes the process with the given pid, `None` if there is no such process.
+pub fn process_info(system: &impl ProcessSystem, pid: u32) -> Option<ProcessInfo> {
+    let process = system
+        .processes()
+        .into_iter()
+        .find(|process| process.pid == pid)?;
+    Some(ProcessInfo {
+        image_path: system.image_path(pid),
+        user: system.user(pid),
+        exe_file: process.exe_file,
+        parent_pid: process.parent_pid,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use alloc::{borrow::ToOwned, vec};
+
+    /// A process of [`FakeSystem`].
+    pub struct FakeProcess {
+        pub pid: u32,
+        pub parent_pid: u32,
+        pub exe_file: &'static str,
+        pub image_path: Option<&'static str>,
+        pub user: Option<&'static str>,
+        pub creation_time: Option<u64>,
+    }
+
+    impl FakeProcess {
+        pub fn new(
+            pid: u32,
+            parent_pid: u32,
+            image_path: &'static str,
+            user: &'static str,
+        ) -> Self {
+            Self {
+                pid,
+                parent_pid,
+                exe_file: image_path.rsplit('\\').next().unwrap(),
+                image_path: Some(image_path),
+                user: Some(user),
+                // processes are started in the order of their pids unless a test says otherwise
+                creation_time: Some(pid.into()),
+            }
+        }
+    }
+
+    /// A fake process table, the installer is `setup.exe` with pid `100`.
+    pub struct FakeSystem {
+        pub current_pid: u32,
+        pub processes: Vec<FakeProcess>,
+    }
+
+    impl FakeSystem {
+        pub fn new() -> Self {
+            Self {
+                current_pid: 100,
+                processes: vec![
+                    FakeProcess {
+                        pid: 4,
+                        parent_pid: 0,
+                        exe_file: "System",
+                        image_path: None,
+                        user: None,
+                        creation_time: Some(0),
+                    },
+                    FakeProcess::new(100, 50, "C:\\Temp\\setup.exe", "PC\\alice"),
+                    FakeProcess::new(1234, 50, "C:\\Program Files\\MyApp\\MyApp.exe", "PC\\alice"),
+                    FakeProcess::new(1240, 1234, "C:\\Program Files\\MyApp\\myapp.exe", "PC\\bob"),
+                    FakeProcess {
+                        image_path: None,
+                        ..FakeProcess::new(2000, 1234, "msedgewebview2.exe", "PC\\alice")
+                    },
+                ],
+            }
+        }
+
+        fn process(&self, pid: u32) -> Option<&FakeProcess> {
+            self.processes.iter().find(|p| p.pid == pid)
+        }
+    }
+
+    impl ProcessSystem for FakeSystem {
+        fn current_pid(&self) -> u32 {
+            self.current_pid
+        }
+
+        fn processes(&self) -> Vec<ProcessEntry> {
+            self.processes
+                .iter()
+                .map(|p| ProcessEntry {
+                    pid: p.pid,
+                    parent_pid: p.parent_pid,
+                    exe_file: p.exe_file.to_owned(),
+                })
+                .collect()
+        }
+
+        fn image_path(&self, pid: u32) -> Option<String> {
+            self.process(pid)?.image_path.map(ToOwned::to_owned)
+        }
+
+        fn user(&self, pid: u32) -> Option<String> {
+            self.process(pid)?.user.map(ToOwned::to_owned)
+        }
+
+        fn creation_time(&self, pid: u32) -> Option<u64> {
+            self.process(pid)?.creation_time
+        }
+
+        fn long_path(&self, path: &str) -> String {
+            path.replace("PROGRA~1", "Program Files")
+        }
+    }
+
+    #[test]
+    fn find_ids() {
+        let system = FakeSystem::new();
+        assert_eq!(find_process_ids(&system, "myapp.exe"), [1234, 1240]);
+        assert_eq!(find_process_ids(&system, "MSEDGEWEBVIEW2.EXE"), [2000]);
+        assert_eq!(find_process_ids(&system, "setup.exe"), [] as [u32; 0]);
+        assert_eq!(find_process_ids(&system, "myapp"), [] as [u32; 0]);
+        assert_eq!(
+            find_process_ids(&system, "myapp.exe|msedge*.exe|setup.exe"),
+            [1234, 1240, 2000]
+        );
+    }
+
+    #[test]
+    fn find_target() {
+        let system = FakeSystem::new();
+        assert_eq!(find_target_ids(&system, "1240"), [1240]);
+        assert_eq!(find_target_ids(&system, "MyApp.exe"), [1234, 1240]);
+        assert_eq!(find_target_ids(&system, "9999"), [] as [u32; 0]);
+        assert_eq!(find_target_ids(&system, "100"), [] as [u32; 0]);
+    }
+
+    #[test]
+    fn tree_kill_order() {
+        let mut system = FakeSystem::new();
+        assert_eq!(
+            find_tree_kill_order(&system, "myapp.exe"),
+            [1240, 2000, 1234]
+        );
+        assert_eq!(find_tree_kill_order(&system, "other.exe"), [] as [u32; 0]);
+
+        // 1234 exited and its pid was reused by an updater started after
+        // msedgewebview2.exe, which is an orphan and isn't killed with it
+        system.processes.retain(|p| p.pid != 1234 && p.pid != 1240);
+        system.processes.push(FakeProcess {
+            creation_time: Some(3000),
+            ..FakeProcess::new(
+                1234,
+                50,
+                "C:\\Program Files\\MyApp\\updater.exe",
+                "PC\\alice",
+            )
+        });
+        system.processes.push(FakeProcess::new(
+            3001,
+            1234,
+            "C:\\Program Files\\MyApp\\helper.exe",
+            "PC\\alice",
+        ));
+        assert_eq!(find_tree_kill_order(&system, "updater.exe"), [3001, 1234]);
+    }
+
+    #[test]
+    fn find_ids_by_path() {
+        let mut system = FakeSystem::new();
+        system.processes.push(FakeProcess::new(
+            3000,
+            50,
+            "\\\\server\\share\\tool.exe",
+            "PC\\alice",
+        ));
+        for (path, pids) in [
+            ("C:\\Program Files\\MyApp\\MyApp.exe", &[1234, 1240][..]),
+            ("c:/program files/myapp/myapp.exe", &[1234, 1240]),
+            ("\\\\?\\C:\\Program Files\\MyApp\\MyApp.exe", &[1234, 1240]),
+            ("C:\\PROGRA~1\\MyApp\\MyApp.exe", &[1234, 1240]),
+            ("C:\\Program Files\\MyApp", &[1234, 1240]),
+            ("C:\\Program Files\\MyApp\\", &[1234, 1240]),
+            ("C:\\Program Files\\Other\\..\\MyApp\\.\\", &[1234, 1240]),
+            ("C:\\Program Files\\My", &[]),
+            ("C:\\Program Files\\MyApp\\MyApp", &[]),
+            ("C:\\Temp", &[]),
+            ("C:\\", &[1234, 1240]),
+            ("\\\\server\\share", &[3000]),
+            ("//server/share/tool.exe", &[3000]),
+            // paths that aren't absolute match nothing, like an empty $INSTDIR
+            ("", &[]),
+            ("\\", &[]),
+            ("/", &[]),
+            (".", &[]),
+            ("..\\..", &[]),
+            ("MyApp", &[]),
+            ("Program Files\\MyApp", &[]),
+            ("\\Program Files\\MyApp", &[]),
+            ("C:MyApp", &[]),
+            ("\\\\server", &[]),
+            ("\\\\?\\", &[]),
+        ] {
+            assert_eq!(find_process_ids_by_path(&system, path), pids, "{path}");
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        for (path, normalized) in [
+            ("C:\\Program Files\\MyApp\\", "c:\\program files\\myapp"),
+            ("\\\\?\\C:\\MyApp", "c:\\myapp"),
+            (
+                "\\\\?\\UNC\\server\\share\\MyApp",
+                "\\\\server\\share\\myapp",
+            ),
+            ("\\\\server\\share\\MyApp", "\\\\server\\share\\myapp"),
+            ("C:/MyApp//bin/../MyApp.exe", "c:\\myapp\\myapp.exe"),
+        ] {
+            assert_eq!(normalize_path(path), normalized, "{path}");
+        }
+    }
+
+    #[test]
+    fn info() {
+        let system = FakeSystem::new();
+        assert_eq!(
+            process_info(&system, 1240),
+            Some(ProcessInfo {
+                exe_file: "myapp.exe".into(),
+                image_path: Some("C:\\Program Files\\MyApp\\myapp.exe".into()),
+                parent_pid: 1234,
+                user: Some("PC\\bob".into()),
+            })
+        );
+        assert_eq!(
+            process_info(&system, 2000),
+            Some(ProcessInfo {
+                exe_file: "msedgewebview2.exe".into(),
+                image_path: None,
+                parent_pid: 1234,
+                user: Some("PC\\alice".into()),
+            })
+        );
+        assert_eq!(process_info(&system, 9999), None);
+    }
+}
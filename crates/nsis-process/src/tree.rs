@@ -0,0 +1,167 @@
+//! Process trees built from the parent pids of a toolhelp snapshot.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+
+use super::system::ProcessEntry;
+
+/// The pids to terminate to kill `roots` and all their descendants, children before their parent.
+///
+/// Parent pids of a snapshot can be stale since pids are reused, so a process created before its parent
+/// according to `creation_time` is an orphan whose parent exited and isn't a descendant. When creation times
+/// are unknown, a stale parent pid can still make a process its own ancestor, so every process is visited once.
+/// The `excluded` process, the installer, and its descendants are skipped.
+pub fn kill_order(
+    processes: &[ProcessEntry],
+    roots: &[u32],
+    excluded: u32,
+    creation_time: impl Fn(u32) -> Option<u64>,
+) -> Vec<u32> {
+    let mut children = BTreeMap::<u32, Vec<u32>>::new();
+    for process in processes {
+        if process.pid != process.parent_pid {
+            children
+                .entry(process.parent_pid)
+                .or_default()
+                .push(process.pid);
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut visited = BTreeSet::new();
+    // (pid, whether its children were pushed already)
+    let mut stack = roots
+        .iter()
+        .rev()
+        .map(|&pid| (pid, false))
+        .collect::<Vec<_>>();
+    while let Some((pid, expanded)) = stack.pop() {
+        if expanded {
+            order.push(pid);
+            continue;
+        }
+        if pid == excluded || !visited.insert(pid) {
+            continue;
+        }
+
+        stack.push((pid, true));
+        let children = children.get(&pid).map_or(&[][..], Vec::as_slice);
+        let parent_time = creation_time(pid);
+        stack.extend(
+            children
+                .iter()
+                .rev()
+                .filter(|&&child| !is_older(creation_time(child), parent_time))
+                .map(|&child| (child, false)),
+        );
+    }
+
+    order
+}
+
+/// Whether a process created at `child` can't be a child of a process created at `parent`.
+fn is_older(child: Option<u64>, parent: Option<u64>) -> bool {
+    matches!((child, parent), (Some(child), Some(parent)) if child < parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::borrow::ToOwned;
+
+    fn snapshot(processes: &[(u32, u32)]) -> Vec<ProcessEntry> {
+        processes
+            .iter()
+            .map(|&(pid, parent_pid)| ProcessEntry {
+                pid,
+                parent_pid,
+                exe_file: "app.exe".to_owned(),
+            })
+            .collect()
+    }
+
+    fn unknown(_: u32) -> Option<u64> {
+        None
+    }
+
+    #[test]
+    fn bottom_up() {
+        // 10 -> 11 -> 13
+        //    -> 12
+        // 20 -> 21
+        let processes = snapshot(&[
+            (0, 0),
+            (10, 1),
+            (11, 10),
+            (12, 10),
+            (13, 11),
+            (20, 1),
+            (21, 20),
+        ]);
+        assert_eq!(
+            kill_order(&processes, &[10], 100, unknown),
+            [13, 11, 12, 10]
+        );
+        assert_eq!(kill_order(&processes, &[11], 100, unknown), [13, 11]);
+        assert_eq!(
+            kill_order(&processes, &[10, 20], 100, unknown),
+            [13, 11, 12, 10, 21, 20]
+        );
+        // a root inside the tree of another root is killed once
+        assert_eq!(
+            kill_order(&processes, &[10, 11], 100, unknown),
+            [13, 11, 12, 10]
+        );
+        assert_eq!(
+            kill_order(&processes, &[11, 10], 100, unknown),
+            [13, 11, 12, 10]
+        );
+        // the idle process is its own parent
+        assert_eq!(kill_order(&processes, &[0], 100, unknown), [0]);
+        assert_eq!(kill_order(&processes, &[], 100, unknown), [] as [u32; 0]);
+    }
+
+    #[test]
+    fn pid_reuse_cycles() {
+        // 10 exited and its pid was reused by a child of 12
+        let processes = snapshot(&[(10, 12), (11, 10), (12, 11)]);
+        assert_eq!(kill_order(&processes, &[11], 100, unknown), [10, 12, 11]);
+
+        let processes = snapshot(&[(10, 10), (11, 10)]);
+        assert_eq!(kill_order(&processes, &[10], 100, unknown), [11, 10]);
+    }
+
+    #[test]
+    fn stale_parents() {
+        // 10 exited and its pid was reused by an app started after 11 and 12
+        let processes = snapshot(&[(10, 1), (11, 10), (12, 10), (13, 10)]);
+        let creation_time = |pid| match pid {
+            10 => Some(300),
+            11 => Some(100),
+            12 => Some(200),
+            13 => Some(400),
+            _ => None,
+        };
+        assert_eq!(kill_order(&processes, &[10], 100, creation_time), [13, 10]);
+        // a process whose creation time can't be read is kept
+        let creation_time = |pid| match pid {
+            10 => Some(300),
+            11 => Some(100),
+            _ => None,
+        };
+        assert_eq!(
+            kill_order(&processes, &[10], 100, creation_time),
+            [12, 13, 10]
+        );
+    }
+
+    #[test]
+    fn excludes_installer() {
+        // the app launched the installer 100 which launched 101
+        let processes = snapshot(&[(10, 1), (11, 10), (100, 10), (101, 100)]);
+        assert_eq!(kill_order(&processes, &[10], 100, unknown), [11, 10]);
+        assert_eq!(kill_order(&processes, &[100], 100, unknown), [] as [u32; 0]);
+    }
+}
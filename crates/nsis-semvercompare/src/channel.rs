@@ -0,0 +1,264 @@
+//! Release channels encoded in pre-release identifiers, like `1.3.0-beta.2`, and the upgrade policy between them.
+
+use alloc::{format, string::String, vec::Vec};
+
+use nsis_plugin_api::Error;
+use semver::Version;
+
+pub const STABLE: &str = "stable";
+pub const UNKNOWN: &str = "unknown";
+
+/// The rules used when none are given, `beta` and `rc` pre-releases are `beta`
+/// and `nightly`, `alpha` and `dev` pre-releases are `nightly`.
+pub const DEFAULT_RULES: &str = "beta=beta,rc;nightly=nightly,alpha,dev";
+
+/// Prefix rules mapping the first pre-release identifier to a channel, in order of decreasing stability.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChannelRules {
+    channels: Vec<(String, Vec<String>)>,
+}
+
+impl ChannelRules {
+    /// Parses `;`-separated `channel=prefix,prefix` rules, like [`DEFAULT_RULES`].
+    ///
+    /// Channels are listed from the most stable to the least stable, all of them are less stable than `stable`.
+    pub fn parse(rules: &str) -> Result<Self, Error> {
+        let rules = if rules.is_empty() {
+            DEFAULT_RULES
+        } else {
+            rules
+        };
+
+        let channels = rules
+            .split(';')
+            .map(|rule| {
+                let invalid = || Error::Custom(format!("Invalid channel rule \"{rule}\""));
+                let (channel, prefixes) = rule.split_once('=').ok_or_else(invalid)?;
+                let prefixes = prefixes
+                    .split(',')
+                    .map(|prefix| prefix.trim().to_lowercase())
+                    .collect::<Vec<_>>();
+                let channel = channel.trim();
+                if channel.is_empty()
+                    || channel == STABLE
+                    || channel == UNKNOWN
+                    || prefixes.iter().any(String::is_empty)
+                {
+                    return Err(invalid());
+                }
+                Ok((channel.into(), prefixes))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { channels })
+    }
+
+    /// Classifies a version into a channel.
+    ///
+    /// Versions without a pre-release are `stable`, pre-releases matching no rule are `unknown`.
+    pub fn classify(&self, version: &Version) -> Channel<'_> {
+        let Some(first) = version.pre.split('.').next().filter(|id| !id.is_empty()) else {
+            return Channel {
+                name: STABLE,
+                rank: 0,
+            };
+        };
+        let first = first.to_lowercase();
+
+        self.channels
+            .iter()
+            .enumerate()
+            .find(|(_, (_, prefixes))| prefixes.iter().any(|p| first.starts_with(p.as_str())))
+            .map(|(i, (name, _))| Channel { name, rank: i + 1 })
+            .unwrap_or(Channel {
+                name: UNKNOWN,
+                rank: self.channels.len() + 1,
+            })
+    }
+}
+
+impl Default for ChannelRules {
+    fn default() -> Self {
+        Self::parse(DEFAULT_RULES).expect("default rules are valid")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel<'a> {
+    pub name: &'a str,
+    /// `0` for `stable`, the higher the less stable.
+    pub rank: usize,
+}
+
+/// Why an upgrade is blocked, pushed as a number by `IsUpgradeAllowed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeVerdict {
+    Allowed = 0,
+    /// The candidate is older than the installed version.
+    Downgrade = 1,
+    /// The candidate is in a less stable channel, like going from `stable` to `nightly`.
+    LessStableChannel = 2,
+    /// The candidate has a different major version.
+    MajorChange = 3,
+}
+
+/// Checks of an upgrade policy, all of them are enabled unless allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpgradePolicy {
+    pub allow_downgrade: bool,
+    pub allow_channel_switch: bool,
+    pub allow_major: bool,
+}
+
+impl UpgradePolicy {
+    /// Parses a `,`-separated list of `allow-downgrade`, `allow-channel-switch` and `allow-major`,
+    /// an empty policy enables all checks.
+    pub fn parse(policy: &str) -> Result<Self, Error> {
+        let mut parsed = Self {
+            allow_downgrade: false,
+            allow_channel_switch: false,
+            allow_major: false,
+        };
+
+        for flag in policy.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match flag {
+                "allow-downgrade" => parsed.allow_downgrade = true,
+                "allow-channel-switch" => parsed.allow_channel_switch = true,
+                "allow-major" => parsed.allow_major = true,
+                _ => return Err(Error::Custom(format!("Invalid policy \"{flag}\""))),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Checks an upgrade from `installed` to `candidate`, a reinstall of the same version is always allowed.
+    pub fn check(
+        &self,
+        rules: &ChannelRules,
+        installed: &Version,
+        candidate: &Version,
+    ) -> UpgradeVerdict {
+        if !self.allow_downgrade && candidate.cmp_precedence(installed).is_lt() {
+            return UpgradeVerdict::Downgrade;
+        }
+        if !self.allow_channel_switch
+            && rules.classify(candidate).rank > rules.classify(installed).rank
+        {
+            return UpgradeVerdict::LessStableChannel;
+        }
+        if !self.allow_major && candidate.major != installed.major {
+            return UpgradeVerdict::MajorChange;
+        }
+        UpgradeVerdict::Allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn classify() {
+        let rules = ChannelRules::default();
+        for (version, channel) in [
+            ("1.3.0", "stable"),
+            ("1.3.0+build.5", "stable"),
+            ("1.3.0-beta.2", "beta"),
+            ("1.3.0-rc.1", "beta"),
+            ("1.3.0-RC1", "beta"),
+            ("1.3.0-nightly.20240101", "nightly"),
+            ("1.3.0-alpha", "nightly"),
+            ("1.3.0-dev.4", "nightly"),
+            ("1.3.0-canary.1", "unknown"),
+            ("1.3.0-1.beta", "unknown"),
+        ] {
+            assert_eq!(rules.classify(&v(version)).name, channel, "{version}");
+        }
+
+        let rules = ChannelRules::parse("lts=lts; preview = pre, rc").unwrap();
+        assert_eq!(
+            rules.classify(&v("2.0.0-lts.1")),
+            Channel {
+                name: "lts",
+                rank: 1
+            }
+        );
+        assert_eq!(
+            rules.classify(&v("2.0.0-rc.1")),
+            Channel {
+                name: "preview",
+                rank: 2
+            }
+        );
+        assert_eq!(
+            rules.classify(&v("2.0.0-beta.1")),
+            Channel {
+                name: "unknown",
+                rank: 3
+            }
+        );
+
+        for rules in [
+            "beta",
+            "=beta",
+            "beta=",
+            "beta=b,,rc",
+            "stable=s",
+            "unknown=u",
+        ] {
+            assert!(ChannelRules::parse(rules).is_err(), "{rules}");
+        }
+    }
+
+    #[test]
+    fn upgrade_policy() {
+        let rules = ChannelRules::default();
+        let strict = UpgradePolicy::parse("").unwrap();
+        for (installed, candidate, verdict) in [
+            ("1.3.0", "1.3.1", UpgradeVerdict::Allowed),
+            ("1.3.0", "1.3.0", UpgradeVerdict::Allowed),
+            ("1.3.0+1", "1.3.0+2", UpgradeVerdict::Allowed),
+            ("1.3.0-beta.2", "1.3.0", UpgradeVerdict::Allowed),
+            ("1.3.0-nightly.1", "1.4.0-beta.1", UpgradeVerdict::Allowed),
+            ("1.3.1", "1.3.0", UpgradeVerdict::Downgrade),
+            ("1.3.0", "1.3.0-beta.2", UpgradeVerdict::Downgrade),
+            (
+                "1.3.0",
+                "1.4.0-nightly.1",
+                UpgradeVerdict::LessStableChannel,
+            ),
+            (
+                "1.3.0-beta.1",
+                "1.3.0-canary.1",
+                UpgradeVerdict::LessStableChannel,
+            ),
+            ("1.3.0", "2.0.0", UpgradeVerdict::MajorChange),
+        ] {
+            assert_eq!(
+                strict.check(&rules, &v(installed), &v(candidate)),
+                verdict,
+                "({installed}, {candidate})"
+            );
+        }
+
+        let lenient =
+            UpgradePolicy::parse("allow-downgrade, allow-channel-switch,allow-major").unwrap();
+        for (installed, candidate) in [
+            ("1.3.1", "1.3.0"),
+            ("1.3.0", "1.4.0-nightly.1"),
+            ("1.3.0", "2.0.0"),
+        ] {
+            assert_eq!(
+                lenient.check(&rules, &v(installed), &v(candidate)),
+                UpgradeVerdict::Allowed
+            );
+        }
+
+        assert!(UpgradePolicy::parse("allow-everything").is_err());
+    }
+}
@@ -1,9 +1,33 @@
 #![no_std]
 
-use core::cmp::Ordering;
+extern crate alloc;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cmp::Ordering, ptr};
 
 use nsis_plugin_api::*;
-use semver::Version;
+use semver::{BuildMetadata, Prerelease, Version, VersionReq};
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, GENERIC_READ, HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW, GetFileSizeEx, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_DELETE, FILE_SHARE_READ,
+        OPEN_EXISTING,
+    },
+    System::Memory::{
+        CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_READ,
+        MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READONLY,
+    },
+};
+
+mod channel;
+mod pe;
+mod scheme;
+
+use scheme::{CalVer, DottedNumeric, NumericVersion, Pep440, Semver, VersionScheme};
 
 nsis_plugin!();
 
@@ -13,26 +37,450 @@ nsis_plugin!();
 ///
 /// # Safety
 ///
-/// This function always expects 2 strings on the stack ($v1, $v2) and will panic otherwise.
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
 #[nsis_fn]
-fn SemverCompare() -> Result<(), Error> {
-    let v1 = popstr()?;
-    let v2 = popstr()?;
+fn SemverCompare(v1: String, v2: String) -> Result<i32, Error> {
+    Ok(compare(&v1, &v2))
+}
 
-    match compare(&v1, &v2) {
-        -1 => push(NEGATIVE_ONE)?,
-        0 => push(ZERO)?,
-        1 => push(ONE)?,
-        _ => unreachable!(),
-    }
+/// Compare two semantic versions ignoring build metadata, as required by SemVer for precedence.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer,
+/// so `1.0.0+20240101` is equal to `1.0.0+20231231`.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverComparePrecedence(v1: String, v2: String) -> Result<i32, Error> {
+    Ok(compare_with_build(&v1, &v2, BuildMetadataMode::Ignore))
+}
 
+/// Compare two semantic versions using numeric build metadata, like build numbers or dates, to break ties.
+///
+/// Versions are compared ignoring build metadata first, then if both versions have build metadata made of
+/// dot-separated numbers these are compared numerically, so `1.0.0+20240101` is newer than `1.0.0+20231231`
+/// and `1.0.0+1.10` is newer than `1.0.0+1.9`. Other build metadata is ignored.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverCompareNumericBuild(v1: String, v2: String) -> Result<i32, Error> {
+    Ok(compare_with_build(&v1, &v2, BuildMetadataMode::Numeric))
+}
+
+/// Compare two semantic versions, failing on invalid versions.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer.
+/// Returns an error message naming the invalid version and why it is invalid if `$v1` or `$v2` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverCompareStrict(v1: String, v2: String) -> Result<i32, Error> {
+    compare_strict(&v1, &v2)
+}
+
+/// Compare two versions parsed leniently, see [`SemverNormalize`] for the accepted versions.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverCompareLenient(v1: String, v2: String) -> Result<i32, Error> {
+    Ok(compare_lenient(&v1, &v2))
+}
+
+/// Normalize a version to a semantic version, accepting versions like `v1.2`, ` 1.0.0` or `1.2.3.0`.
+///
+/// Surrounding whitespace and a leading `v` or `V` are removed, missing minor and patch components are set to `0`
+/// and a fourth component is handled according to `$revision`:
+/// - `build` or an empty string: the fourth component becomes build metadata, `1.2.3.4` becomes `1.2.3+4`.
+/// - `ignore`: the fourth component is removed, `1.2.3.4` becomes `1.2.3`.
+///
+/// Returns the normalized version or an error message if the version is still invalid.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($version, $revision) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverNormalize(version: String, revision: String) -> Result<String, Error> {
+    let revision = Revision::parse(&revision)?;
+    parse_lenient(&version, revision)
+        .map(|version| version.to_string())
+        .map_err(|e| Error::Custom(format!("Invalid version \"{version}\": {e}")))
+}
+
+/// Compare two dotted numeric versions with any number of components, like Windows file versions
+/// `major.minor.build.revision`, where each component fits in 32 bits.
+///
+/// Missing components are `0` so `1.2` is equal to `1.2.0.0`. A semantic version is compared by its
+/// `major.minor.patch` components and if these are equal, a pre-release is older than a version without one,
+/// so `1.2.3-beta.1` is older than `1.2.3.0` and `1.2.3.1` is newer than `1.2.3`. Build metadata is ignored.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer.
+/// Invalid versions are older than any valid version and equal to each other.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn VersionCompare4(v1: String, v2: String) -> Result<i32, Error> {
+    Ok(compare_numeric(&v1, &v2))
+}
+
+/// Compare two versions using a named version scheme.
+///
+/// `$scheme` is one of:
+/// - `semver`: semantic versions, like `SemverCompare`.
+/// - `dotted-numeric`: dotted numeric versions with any number of components, like `VersionCompare4`.
+/// - `calver`: calendar versions like `2024.10.3`, `24.04` or `2024.10.3-beta.1` and date stamps like `20240101`.
+///   Two-digit years are in the 2000s and a `-modifier` is older than the version without it.
+/// - `pep440`: Python package versions like `1!2.0.post1`, `1.0rc1`, `1.0.dev3` or `1.0+ubuntu.1`.
+///
+/// Returns `0` if equal, `1` if `$v1` is newer and `-1` if `$v2` is newer.
+/// Invalid versions are older than any valid version and equal to each other.
+/// Returns an error message if `$scheme` is unknown.
+///
+/// # Safety
+///
+/// This function always expects 3 strings on the stack ($scheme, $v1, $v2) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn VersionCompareWith(scheme: String, v1: String, v2: String) -> Result<i32, Error> {
+    compare_with_scheme(&scheme, &v1, &v2)
+}
+
+/// Compare the file version of a PE file (`.exe` or `.dll`) with a version.
+///
+/// The file version is read from the fixed file info of the version resource, the one shown by Windows Explorer,
+/// and compared like `VersionCompare4`.
+///
+/// Returns `0` if equal, `1` if the file is newer and `-1` if `$version` is newer.
+/// Returns an error message if the file can't be read or has no version resource.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($path, $version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn CompareFileVersion(path: String, version: String) -> Result<i32, Error> {
+    let file = MappedFile::open(&path)
+        .ok_or_else(|| Error::Custom(format!("Failed to read \"{path}\"")))?;
+    compare_file_version(file.data(), &version)
+}
+
+/// Find the newest of a list of semantic versions.
+///
+/// Invalid versions are older than any valid version, like `SemverCompare`.
+///
+/// Returns the newest version or an empty string if `$count` is `0`.
+///
+/// # Safety
+///
+/// This function always expects an integer ($count) followed by `$count` strings on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverMax(count: i32) -> Result<Option<String>, Error> {
+    let versions = pop_versions(count)?;
+    Ok(versions
+        .into_iter()
+        .max_by(|v1, v2| compare_ordering(v1, v2)))
+}
+
+/// Find the oldest of a list of semantic versions.
+///
+/// Invalid versions are older than any valid version, like `SemverCompare`.
+///
+/// Returns the oldest version or an empty string if `$count` is `0`.
+///
+/// # Safety
+///
+/// This function always expects an integer ($count) followed by `$count` strings on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverMin(count: i32) -> Result<Option<String>, Error> {
+    let versions = pop_versions(count)?;
+    Ok(versions
+        .into_iter()
+        .min_by(|v1, v2| compare_ordering(v1, v2)))
+}
+
+/// Sort a list of semantic versions from oldest to newest.
+///
+/// Invalid versions are older than any valid version, like `SemverCompare`,
+/// and equal versions keep their order.
+///
+/// Returns the `$count` sorted versions, the oldest version is popped first.
+///
+/// # Safety
+///
+/// This function always expects an integer ($count) followed by `$count` strings on the stack and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverSort(count: i32) -> Result<(), Error> {
+    let mut versions = pop_versions(count)?;
+    versions.sort_by(|v1, v2| compare_ordering(v1, v2));
+    for version in versions.iter().rev() {
+        pushstr(version)?;
+    }
     Ok(())
 }
 
+/// Get the major component of a semantic version, `1` for `1.2.3-beta.1+abc`.
+///
+/// Returns an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverGetMajor(version: String) -> Result<u64, Error> {
+    Ok(parse_strict(&version, "$version")?.major)
+}
+
+/// Get the minor component of a semantic version, `2` for `1.2.3-beta.1+abc`.
+///
+/// Returns an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverGetMinor(version: String) -> Result<u64, Error> {
+    Ok(parse_strict(&version, "$version")?.minor)
+}
+
+/// Get the patch component of a semantic version, `3` for `1.2.3-beta.1+abc`.
+///
+/// Returns an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverGetPatch(version: String) -> Result<u64, Error> {
+    Ok(parse_strict(&version, "$version")?.patch)
+}
+
+/// Get the pre-release of a semantic version, `beta.1` for `1.2.3-beta.1+abc`.
+///
+/// Returns an empty string if `$version` has no pre-release or an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverGetPre(version: String) -> Result<String, Error> {
+    Ok(parse_strict(&version, "$version")?.pre.to_string())
+}
+
+/// Get the build metadata of a semantic version, `abc` for `1.2.3-beta.1+abc`.
+///
+/// Returns an empty string if `$version` has no build metadata or an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverGetBuild(version: String) -> Result<String, Error> {
+    Ok(parse_strict(&version, "$version")?.build.to_string())
+}
+
+/// Test if a semantic version is a pre-release.
+///
+/// Returns `1` if `$version` has a pre-release, `0` if it doesn't or an error message if `$version` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverIsPrerelease(version: String) -> Result<bool, Error> {
+    Ok(!parse_strict(&version, "$version")?.pre.is_empty())
+}
+
+/// Increment a component of a semantic version, `$component` is `major`, `minor` or `patch`.
+///
+/// Lower components are reset to `0` and build metadata is removed. A pre-release is released
+/// instead when it precedes the incremented version, so bumping the patch of `1.2.3-beta.1` gives `1.2.3`
+/// and bumping the minor of `1.3.0-beta.1` gives `1.3.0`.
+///
+/// Returns the incremented version or an error message if `$version` or `$component` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($version, $component) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverBump(version: String, component: String) -> Result<String, Error> {
+    let version = parse_strict(&version, "$version")?;
+    bump(version, &component).map(|version| version.to_string())
+}
+
+/// Test if a string is a valid semantic version.
+///
+/// Returns `1` if `$version` is valid and `0` otherwise.
+///
+/// # Safety
+///
+/// This function always expects 1 string on the stack ($version) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverValidate(version: String) -> Result<bool, Error> {
+    Ok(Version::parse(&version).is_ok())
+}
+
+/// Test if a semantic version matches a version requirement, like `>=1.2, <2.0` or `^1.3`.
+///
+/// Returns `1` if `$version` matches `$requirement` and `0` otherwise, including when `$version` is invalid.
+/// Returns an error message if `$requirement` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($version, $requirement) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverMatches(version: String, requirement: String) -> Result<bool, Error> {
+    matches(&version, &requirement)
+}
+
+/// Classify a semantic version into a release channel using prefix rules on its first pre-release identifier.
+///
+/// `$rules` is a `;`-separated list of `channel=prefix,prefix` rules, like `beta=beta,rc;nightly=nightly,alpha,dev`
+/// which are the rules used when `$rules` is empty. Prefixes are case-insensitive and the first matching rule wins.
+///
+/// Returns `stable` for versions without a pre-release, the channel of the first matching rule or `unknown`
+/// if no rule matches. Returns an error message if `$version` or `$rules` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 2 strings on the stack ($version, $rules) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn SemverChannel(version: String, rules: String) -> Result<String, Error> {
+    let rules = channel::ChannelRules::parse(&rules)?;
+    let version = parse_strict(&version, "$version")?;
+    Ok(rules.classify(&version).name.into())
+}
+
+/// Test if upgrading from the `$installed` version to the `$candidate` version is allowed by `$policy`.
+///
+/// Channels are classified with the default rules of `SemverChannel`, from the most stable to the least stable:
+/// `stable`, `beta`, `nightly` and `unknown`.
+///
+/// `$policy` is a `,`-separated list of checks to skip, an empty policy runs all of them:
+/// - `allow-downgrade`: allow a `$candidate` older than `$installed`, build metadata is ignored.
+/// - `allow-channel-switch`: allow a `$candidate` in a less stable channel, like `stable` to `nightly`.
+/// - `allow-major`: allow a `$candidate` with a different major version.
+///
+/// Returns `0` if the upgrade is allowed, or the first failed check: `1` for a downgrade,
+/// `2` for a less stable channel and `3` for a major version change.
+/// Returns an error message if a version or `$policy` is invalid.
+///
+/// # Safety
+///
+/// This function always expects 3 strings on the stack ($installed, $candidate, $policy) and pushes an error message instead of running otherwise.
+#[nsis_fn]
+fn IsUpgradeAllowed(installed: String, candidate: String, policy: String) -> Result<i32, Error> {
+    let installed = parse_strict(&installed, "$installed")?;
+    let candidate = parse_strict(&candidate, "$candidate")?;
+    let policy = channel::UpgradePolicy::parse(&policy)?;
+    Ok(policy.check(&channel::ChannelRules::default(), &installed, &candidate) as i32)
+}
+
+fn matches(version: &str, requirement: &str) -> Result<bool, Error> {
+    let requirement = VersionReq::parse(requirement)
+        .map_err(|e| Error::Custom(format!("Invalid version requirement: {e}")))?;
+
+    Ok(Version::parse(version).is_ok_and(|version| requirement.matches(&version)))
+}
+
 fn compare(v1: &str, v2: &str) -> i32 {
-    let v1 = Version::parse(v1);
-    let v2 = Version::parse(v2);
+    compare_scheme::<Semver>(v1, v2)
+}
+
+fn compare_scheme<S: VersionScheme>(v1: &str, v2: &str) -> i32 {
+    compare_parsed(S::parse(v1).ok_or(()), S::parse(v2).ok_or(()))
+}
+
+fn compare_with_scheme(scheme: &str, v1: &str, v2: &str) -> Result<i32, Error> {
+    match scheme {
+        "semver" => Ok(compare_scheme::<Semver>(v1, v2)),
+        "dotted-numeric" => Ok(compare_scheme::<DottedNumeric>(v1, v2)),
+        "calver" => Ok(compare_scheme::<CalVer>(v1, v2)),
+        "pep440" => Ok(compare_scheme::<Pep440>(v1, v2)),
+        _ => Err(Error::Custom(format!("Invalid scheme \"{scheme}\""))),
+    }
+}
+
+fn compare_ordering(v1: &str, v2: &str) -> Ordering {
+    compare(v1, v2).cmp(&0)
+}
+
+/// Pops the `count` versions following the count of [`SemverMax`], [`SemverMin`] and [`SemverSort`].
+///
+/// # Safety
+///
+/// This function reads static variables and should only be called after [`exdll_init`] is called.
+unsafe fn pop_versions(count: i32) -> Result<Vec<String>, Error> {
+    let count =
+        usize::try_from(count).map_err(|_| Error::Custom(format!("Invalid count \"{count}\"")))?;
+    (0..count).map(|_| popstr()).collect()
+}
 
+fn compare_lenient(v1: &str, v2: &str) -> i32 {
+    compare_parsed(
+        parse_lenient(v1, Revision::Build),
+        parse_lenient(v2, Revision::Build),
+    )
+}
+
+fn compare_numeric(v1: &str, v2: &str) -> i32 {
+    compare_scheme::<DottedNumeric>(v1, v2)
+}
+
+/// How build metadata is compared once versions are equal otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuildMetadataMode {
+    Ignore,
+    Numeric,
+}
+
+fn compare_with_build(v1: &str, v2: &str, mode: BuildMetadataMode) -> i32 {
+    compare_parsed_by(Version::parse(v1), Version::parse(v2), |v1, v2| {
+        let precedence =
+            (v1.major, v1.minor, v1.patch, &v1.pre).cmp(&(v2.major, v2.minor, v2.patch, &v2.pre));
+        match mode {
+            BuildMetadataMode::Ignore => precedence,
+            BuildMetadataMode::Numeric => precedence.then_with(|| {
+                match (numeric_build(&v1.build), numeric_build(&v2.build)) {
+                    (Some(b1), Some(b2)) => b1.cmp(&b2),
+                    _ => Ordering::Equal,
+                }
+            }),
+        }
+    })
+}
+
+/// Parses build metadata made of dot-separated numbers.
+fn numeric_build(build: &BuildMetadata) -> Option<Vec<u64>> {
+    build
+        .split('.')
+        .map(|id| {
+            id.parse()
+                .ok()
+                .filter(|_| id.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .collect()
+}
+
+/// Invalid versions are older than any valid version and equal to each other.
+fn compare_parsed<T: Ord, E>(v1: Result<T, E>, v2: Result<T, E>) -> i32 {
+    compare_parsed_by(v1, v2, T::cmp)
+}
+
+fn compare_parsed_by<T, E>(
+    v1: Result<T, E>,
+    v2: Result<T, E>,
+    cmp: impl FnOnce(&T, &T) -> Ordering,
+) -> i32 {
     let (v1, v2) = match (v1, v2) {
         (Ok(_), Err(_)) => return 1,
         (Err(_), Err(_)) => return 0,
@@ -40,7 +488,205 @@ fn compare(v1: &str, v2: &str) -> i32 {
         (Ok(v1), Ok(v2)) => (v1, v2),
     };
 
-    match v1.cmp(&v2) {
+    ordering_to_int(cmp(&v1, &v2))
+}
+
+fn compare_strict(v1: &str, v2: &str) -> Result<i32, Error> {
+    let v1 = parse_strict(v1, "$v1")?;
+    let v2 = parse_strict(v2, "$v2")?;
+
+    Ok(ordering_to_int(v1.cmp(&v2)))
+}
+
+/// Parses a version, the error message names the argument the version was passed as.
+fn parse_strict(version: &str, argument: &str) -> Result<Version, Error> {
+    Version::parse(version)
+        .map_err(|e| Error::Custom(format!("Invalid {argument} \"{version}\": {e}")))
+}
+
+fn compare_file_version(data: &[u8], version: &str) -> Result<i32, Error> {
+    let fixed = pe::read_version_info(data)
+        .and_then(|info| info.fixed.ok_or(pe::PeError::NoVersionInfo))
+        .map_err(|e| Error::Custom(e.to_string()))?;
+
+    let file_version = NumericVersion {
+        components: fixed.file_version.map(u32::from).to_vec(),
+        pre: Prerelease::EMPTY,
+    };
+    Ok(compare_parsed(
+        Ok(file_version),
+        DottedNumeric::parse(version).ok_or(()),
+    ))
+}
+
+/// A read-only memory mapping of a whole file.
+struct MappedFile {
+    file: HANDLE,
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    len: usize,
+}
+
+impl MappedFile {
+    fn open(path: &str) -> Option<Self> {
+        let mut mapped = Self {
+            file: INVALID_HANDLE_VALUE,
+            mapping: ptr::null_mut(),
+            view: MEMORY_MAPPED_VIEW_ADDRESS {
+                Value: ptr::null_mut(),
+            },
+            len: 0,
+        };
+
+        unsafe {
+            mapped.file = CreateFileW(
+                encode_utf16(path).as_ptr(),
+                GENERIC_READ,
+                FILE_SHARE_READ | FILE_SHARE_DELETE,
+                ptr::null(),
+                OPEN_EXISTING,
+                FILE_ATTRIBUTE_NORMAL,
+                ptr::null_mut(),
+            );
+            if mapped.file == INVALID_HANDLE_VALUE {
+                return None;
+            }
+
+            let mut len = 0;
+            if GetFileSizeEx(mapped.file, &mut len) == 0 {
+                return None;
+            }
+            mapped.len = usize::try_from(len).ok()?;
+
+            // mapping an empty file fails
+            if mapped.len == 0 {
+                return Some(mapped);
+            }
+
+            mapped.mapping =
+                CreateFileMappingW(mapped.file, ptr::null(), PAGE_READONLY, 0, 0, ptr::null());
+            if mapped.mapping.is_null() {
+                return None;
+            }
+
+            mapped.view = MapViewOfFile(mapped.mapping, FILE_MAP_READ, 0, 0, 0);
+            if mapped.view.Value.is_null() {
+                return None;
+            }
+        }
+
+        Some(mapped)
+    }
+
+    fn data(&self) -> &[u8] {
+        if self.view.Value.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.view.Value as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.view.Value.is_null() {
+                UnmapViewOfFile(self.view);
+            }
+            if !self.mapping.is_null() {
+                CloseHandle(self.mapping);
+            }
+            if self.file != INVALID_HANDLE_VALUE {
+                CloseHandle(self.file);
+            }
+        }
+    }
+}
+
+fn bump(version: Version, component: &str) -> Result<Version, Error> {
+    let Version {
+        major,
+        minor,
+        patch,
+        pre,
+        ..
+    } = version;
+    let is_prerelease = !pre.is_empty();
+    let (major, minor, patch) = match component {
+        "major" if is_prerelease && minor == 0 && patch == 0 => (major, 0, 0),
+        "major" => (major + 1, 0, 0),
+        "minor" if is_prerelease && patch == 0 => (major, minor, 0),
+        "minor" => (major, minor + 1, 0),
+        "patch" if is_prerelease => (major, minor, patch),
+        "patch" => (major, minor, patch + 1),
+        _ => return Err(Error::Custom(format!("Invalid component \"{component}\""))),
+    };
+    Ok(Version::new(major, minor, patch))
+}
+
+/// What to do with the fourth component of versions like `1.2.3.4` when parsing leniently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Revision {
+    Build,
+    Ignore,
+}
+
+impl Revision {
+    fn parse(revision: &str) -> Result<Self, Error> {
+        match revision {
+            "" | "build" => Ok(Revision::Build),
+            "ignore" => Ok(Revision::Ignore),
+            _ => Err(Error::Custom(format!("Invalid revision \"{revision}\""))),
+        }
+    }
+}
+
+/// Parses a version after removing surrounding whitespace and a leading `v` or `V`,
+/// padding missing minor and patch components and handling a fourth component according to `revision`.
+fn parse_lenient(version: &str, revision: Revision) -> Result<Version, semver::Error> {
+    let version = version.trim();
+    let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+
+    let (core, suffix) = version.split_at(version.find(['-', '+']).unwrap_or(version.len()));
+    let components = core
+        .split('.')
+        .map(|c| {
+            c.parse::<u64>()
+                .ok()
+                .filter(|_| c.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .collect::<Option<Vec<_>>>();
+
+    let Some(components) = components.filter(|c| (1..=4).contains(&c.len())) else {
+        // let semver report the error
+        return Version::parse(version);
+    };
+
+    let (pre, build) = match suffix.split_once('+') {
+        Some((pre, build)) => (pre, Some(build)),
+        None => (suffix, None),
+    };
+
+    let mut normalized = format!(
+        "{}.{}.{}{pre}",
+        components[0],
+        components.get(1).unwrap_or(&0),
+        components.get(2).unwrap_or(&0),
+    );
+    match (
+        components.get(3).filter(|_| revision == Revision::Build),
+        build,
+    ) {
+        (Some(rev), Some(build)) => normalized.push_str(&format!("+{rev}.{build}")),
+        (Some(rev), None) => normalized.push_str(&format!("+{rev}")),
+        (None, Some(build)) => normalized.push_str(&format!("+{build}")),
+        (None, None) => {}
+    }
+
+    Version::parse(&normalized)
+}
+
+fn ordering_to_int(ordering: Ordering) -> i32 {
+    match ordering {
         Ordering::Greater => 1,
         Ordering::Equal => 0,
         Ordering::Less => -1,
@@ -71,4 +717,325 @@ mod tests {
             assert_eq!(compare(v1, v2), ret);
         }
     }
+
+    #[test]
+    fn build_metadata() {
+        for (v1, v2, full, precedence, numeric) in [
+            ("1.0.0+20240101", "1.0.0+20231231", 1, 0, 1),
+            ("1.0.0+1.10", "1.0.0+1.9", 1, 0, 1),
+            ("1.0.0+1.9", "1.0.0+1.9.0", -1, 0, -1),
+            ("1.0.0+abc", "1.0.0+def", -1, 0, 0),
+            ("1.0.0+5", "1.0.0", 1, 0, 0),
+            ("1.0.0+5", "1.0.0+abc", -1, 0, 0),
+            ("1.0.1+1", "1.0.0+2", 1, 1, 1),
+            ("1.0.0-beta+2", "1.0.0+1", -1, -1, -1),
+            ("1.0.0-beta+2", "1.0.0-beta+10", -1, 0, -1),
+            ("1.0.0+1", "invalid", 1, 1, 1),
+        ] {
+            assert_eq!(compare(v1, v2), full, "({v1}, {v2})");
+            assert_eq!(
+                compare_with_build(v1, v2, BuildMetadataMode::Ignore),
+                precedence,
+                "({v1}, {v2})"
+            );
+            assert_eq!(
+                compare_with_build(v1, v2, BuildMetadataMode::Numeric),
+                numeric,
+                "({v1}, {v2})"
+            );
+        }
+    }
+
+    #[test]
+    fn strict() {
+        for (v1, v2, ret) in [
+            ("1.2.1", "1.2.0", 1),
+            ("1.2.0", "1.2.1", -1),
+            ("1.2.1-alpha.1", "1.2.1-alpha.1", 0),
+        ] {
+            assert_eq!(compare_strict(v1, v2).unwrap(), ret);
+        }
+
+        for (v1, v2, err) in [
+            (
+                "1.2.saf1",
+                "-q1.2.1",
+                "Invalid $v1 \"1.2.saf1\": unexpected character 's' while parsing patch version number",
+            ),
+            (
+                "1.2.1",
+                " 1.0.0",
+                "Invalid $v2 \" 1.0.0\": unexpected character ' ' while parsing major version number",
+            ),
+        ] {
+            let Err(Error::Custom(message)) = compare_strict(v1, v2) else {
+                panic!("expected an error for ({v1}, {v2})");
+            };
+            assert_eq!(message, err);
+        }
+    }
+
+    #[test]
+    fn lenient() {
+        for (version, revision, normalized) in [
+            ("1.2.3", Revision::Build, "1.2.3"),
+            ("v1.2", Revision::Build, "1.2.0"),
+            ("V1", Revision::Build, "1.0.0"),
+            (" 1.0.0 ", Revision::Build, "1.0.0"),
+            ("1.02.3", Revision::Build, "1.2.3"),
+            ("1.2.3.0", Revision::Build, "1.2.3+0"),
+            ("1.2.3.4", Revision::Ignore, "1.2.3"),
+            ("v1.2-beta.1", Revision::Build, "1.2.0-beta.1"),
+            ("1.2.3.4-rc.1+abc", Revision::Build, "1.2.3-rc.1+4.abc"),
+            ("1.2.3.4+abc", Revision::Ignore, "1.2.3+abc"),
+        ] {
+            assert_eq!(
+                parse_lenient(version, revision).unwrap().to_string(),
+                normalized
+            );
+        }
+
+        for version in ["", "v", "1.2.3.4.5", "1..2", "1.2a", "1.2.3-", "-1.2"] {
+            assert!(parse_lenient(version, Revision::Build).is_err());
+        }
+
+        for (v1, v2, ret) in [
+            ("v1.2", "1.2.0", 0),
+            ("1.2.3.10", "1.2.3.4", 1),
+            ("1.2.3.0", "1.2.4", -1),
+            (" 1.0.0-aluc.1", "1.0.0-bdfsf.0", -1),
+            ("1.2.saf1", "1.2", -1),
+        ] {
+            assert_eq!(compare_lenient(v1, v2), ret);
+        }
+    }
+
+    #[test]
+    fn numeric() {
+        for (v1, v2, ret) in [
+            ("1.2.3.4", "1.2.3.4", 0),
+            ("1.2.3.10", "1.2.3.9", 1),
+            ("1.2", "1.2.0.0", 0),
+            ("1.2.0.1", "1.2", 1),
+            ("10.0.19041.1", "6.3.9600.16384", 1),
+            ("1.2.3.4.5", "1.2.3.4", 1),
+            ("4294967295.0", "4294967294.9", 1),
+            ("1.2.3", "1.2.3.0", 0),
+            ("1.2.3-beta.1", "1.2.3.0", -1),
+            ("1.2.3-beta.1", "1.2.2.9", 1),
+            ("1.2.3-beta.2", "1.2.3-beta.1", 1),
+            ("1.2.3+20240101", "1.2.3.0", 0),
+            ("1.2.3.1", "1.2.3-rc.1", 1),
+            ("4294967296.0", "1.0", -1),
+            ("1.2.a", "1.0", -1),
+            ("1..2", "", 0),
+            (" 1.2", "1.2", -1),
+        ] {
+            assert_eq!(compare_numeric(v1, v2), ret, "({v1}, {v2})");
+        }
+    }
+
+    #[test]
+    fn file_version() {
+        let app = include_bytes!("../testdata/app-x86.dll");
+        for (version, ret) in [
+            ("1.2.3.4", 0),
+            ("1.2.3", 1),
+            ("1.2.3.5", -1),
+            ("1.2.3-beta.1", 1),
+            ("1.2.4-beta.1", -1),
+            ("invalid", 1),
+        ] {
+            assert_eq!(compare_file_version(app, version).unwrap(), ret);
+        }
+
+        let Err(Error::Custom(message)) = compare_file_version(b"MZ", "1.0.0") else {
+            panic!("expected an error");
+        };
+        assert_eq!(message, "Not a PE file");
+    }
+
+    #[test]
+    fn bump_version() {
+        for (version, component, bumped) in [
+            ("1.2.3", "major", "2.0.0"),
+            ("1.2.3", "minor", "1.3.0"),
+            ("1.2.3", "patch", "1.2.4"),
+            ("1.2.3+abc", "patch", "1.2.4"),
+            ("1.2.3-beta.1", "major", "2.0.0"),
+            ("1.2.3-beta.1", "minor", "1.3.0"),
+            ("1.2.3-beta.1", "patch", "1.2.3"),
+            ("1.3.0-beta.1", "minor", "1.3.0"),
+            ("2.0.0-rc.1", "major", "2.0.0"),
+            ("2.0.0-rc.1+abc", "patch", "2.0.0"),
+        ] {
+            let version = Version::parse(version).unwrap();
+            assert_eq!(bump(version, component).unwrap().to_string(), bumped);
+        }
+
+        assert!(bump(Version::new(1, 2, 3), "build").is_err());
+    }
+
+    #[test]
+    fn matches_requirement() {
+        for (version, requirement, ret) in [
+            ("1.4.2", ">=1.2, <2.0", true),
+            ("2.0.0", ">=1.2, <2.0", false),
+            ("1.1.9", ">=1.2, <2.0", false),
+            ("1.3.0", "^1.3", true),
+            ("1.9.9", "^1.3", true),
+            ("2.0.0", "^1.3", false),
+            ("1.3.0-beta.1", "^1.3", false),
+            ("1.3.0-beta.2", ">=1.3.0-beta.1", true),
+            ("1.2.3", "*", true),
+            ("1.2qe2.1", "*", false),
+        ] {
+            assert_eq!(matches(version, requirement).unwrap(), ret);
+        }
+
+        assert!(matches("1.0.0", ">=1.2 <2.0 ||").is_err());
+    }
+
+    #[test]
+    fn export_compare() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(SemverCompare, &["1.0.0", "1.1.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("-1"));
+    }
+
+    #[test]
+    fn export_matches() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(SemverMatches, &["1.4.2", ">=1.2, <2.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("1"));
+        nsis.call(SemverMatches, &["1.4.2", "=>1.2"]);
+        assert!(nsis
+            .pop()
+            .unwrap()
+            .starts_with("Invalid version requirement: "));
+    }
+
+    #[test]
+    fn export_strict() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(SemverCompareStrict, &["1.0.0", "1.a.0"]);
+        assert_eq!(
+            nsis.pop().as_deref(),
+            Some("Invalid $v2 \"1.a.0\": unexpected character 'a' while parsing minor version number")
+        );
+        nsis.call(SemverValidate, &["1.0.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("1"));
+        nsis.call(SemverValidate, &["v1.0.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn export_lenient() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(SemverNormalize, &["v1.2.3.4", ""]);
+        assert_eq!(nsis.pop().as_deref(), Some("1.2.3+4"));
+        nsis.call(SemverNormalize, &["v1.2.3.4", "ignore"]);
+        assert_eq!(nsis.pop().as_deref(), Some("1.2.3"));
+        nsis.call(SemverCompareLenient, &["v1.2", "1.2.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn export_compare4() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(VersionCompare4, &["1.2.3.10", "1.2.3-rc.1"]);
+        assert_eq!(nsis.pop().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn export_sort() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        let versions = ["4", "1.10.0", "1.2.0", "invalid", "1.9.0-beta.1", "1.2.0"];
+        nsis.call(SemverMax, &[&["6"], &versions[..]].concat());
+        assert_eq!(nsis.pop().as_deref(), Some("1.10.0"));
+        nsis.call(SemverMin, &[&["6"], &versions[..]].concat());
+        assert_eq!(nsis.pop().as_deref(), Some("4"));
+        nsis.call(SemverSort, &[&["6"], &versions[..]].concat());
+        assert_eq!(
+            nsis.stack(),
+            ["4", "invalid", "1.2.0", "1.2.0", "1.9.0-beta.1", "1.10.0"]
+        );
+        for _ in 0..6 {
+            nsis.pop();
+        }
+        nsis.call(SemverMax, &["0"]);
+        assert_eq!(nsis.pop().as_deref(), Some(""));
+        nsis.call(SemverSort, &["-1"]);
+        assert_eq!(nsis.pop().as_deref(), Some("Invalid count \"-1\""));
+    }
+
+    #[test]
+    fn export_components() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        let version = "1.2.3-beta.1+abc";
+        nsis.call(SemverGetMajor, &[version])
+            .call(SemverGetMinor, &[version])
+            .call(SemverGetPatch, &[version])
+            .call(SemverGetPre, &[version])
+            .call(SemverGetBuild, &[version])
+            .call(SemverIsPrerelease, &[version])
+            .call(SemverIsPrerelease, &["1.2.3"])
+            .call(SemverBump, &[version, "minor"]);
+        assert_eq!(
+            nsis.stack(),
+            ["1.3.0", "0", "1", "abc", "beta.1", "3", "2", "1"]
+        );
+        for _ in 0..8 {
+            nsis.pop();
+        }
+        nsis.call(SemverGetMajor, &["1.2"]);
+        assert_eq!(
+            nsis.pop().as_deref(),
+            Some("Invalid $version \"1.2\": unexpected end of input while parsing minor version number")
+        );
+    }
+
+    #[test]
+    fn export_build_metadata() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(
+            SemverComparePrecedence,
+            &["1.0.0+20240101", "1.0.0+20231231"],
+        );
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+        nsis.call(
+            SemverCompareNumericBuild,
+            &["1.0.0+20231231", "1.0.0+20240101"],
+        );
+        assert_eq!(nsis.pop().as_deref(), Some("-1"));
+    }
+
+    #[test]
+    fn export_scheme() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(VersionCompareWith, &["pep440", "1.0rc1", "1.0.post1"]);
+        assert_eq!(nsis.pop().as_deref(), Some("-1"));
+        nsis.call(VersionCompareWith, &["calver", "24.04", "2024.4.0"]);
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+        nsis.call(VersionCompareWith, &["ubuntu", "24.04", "22.04"]);
+        assert_eq!(nsis.pop().as_deref(), Some("Invalid scheme \"ubuntu\""));
+    }
+
+    #[test]
+    fn export_channel() {
+        let mut nsis = nsis_plugin_test::Runtime::new();
+        nsis.call(SemverChannel, &["1.3.0-beta.2", ""]);
+        assert_eq!(nsis.pop().as_deref(), Some("beta"));
+        nsis.call(SemverChannel, &["1.3.0-canary.2", "canary=canary"]);
+        assert_eq!(nsis.pop().as_deref(), Some("canary"));
+        nsis.call(IsUpgradeAllowed, &["1.3.0", "1.4.0-nightly.1", ""]);
+        assert_eq!(nsis.pop().as_deref(), Some("2"));
+        nsis.call(IsUpgradeAllowed, &["1.3.0", "2.0.0", "allow-major"]);
+        assert_eq!(nsis.pop().as_deref(), Some("0"));
+        nsis.call(IsUpgradeAllowed, &["", "2.0.0", ""]);
+        assert_eq!(
+            nsis.pop().as_deref(),
+            Some("Invalid $installed \"\": empty string, expected a semver version")
+        );
+    }
 }
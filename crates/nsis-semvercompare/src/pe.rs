@@ -0,0 +1,346 @@
+//! Reads the version resource (`VS_VERSIONINFO`) of PE files (`.exe` and `.dll`).
+//!
+//! This is a plain byte parser so it works on any host.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+const IMAGE_DOS_SIGNATURE: &[u8] = b"MZ";
+const IMAGE_NT_SIGNATURE: &[u8] = b"PE\0\0";
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+const RT_VERSION: u32 = 16;
+const VS_FFI_SIGNATURE: u32 = 0xFEEF04BD;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeError {
+    /// The file isn't a PE file.
+    NotPe,
+    /// The file has no version resource.
+    NoVersionInfo,
+    /// The file or its version resource is truncated or corrupt.
+    Malformed,
+}
+
+impl fmt::Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PeError::NotPe => "Not a PE file",
+            PeError::NoVersionInfo => "No version resource",
+            PeError::Malformed => "Malformed PE file",
+        })
+    }
+}
+
+/// The numeric versions of `VS_FIXEDFILEINFO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedFileInfo {
+    /// `major.minor.build.revision`
+    pub file_version: [u16; 4],
+    /// `major.minor.build.revision`
+    pub product_version: [u16; 4],
+    pub file_flags: u32,
+    pub file_os: u32,
+    pub file_type: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub fixed: Option<FixedFileInfo>,
+    /// The `FileVersion` string of the first string table that has one.
+    pub file_version: Option<String>,
+    /// The `ProductVersion` string of the first string table that has one.
+    pub product_version: Option<String>,
+}
+
+/// Reads the version resource of a PE file.
+pub fn read_version_info(data: &[u8]) -> Result<VersionInfo, PeError> {
+    let resource = find_version_resource(data)?;
+    parse_version_info(resource)
+}
+
+/// Returns `len` bytes at `offset`, offsets are read from the file so they can be anything.
+fn bytes_at(data: &[u8], offset: usize, len: usize) -> Result<&[u8], PeError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(PeError::Malformed)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, PeError> {
+    bytes_at(data, offset, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, PeError> {
+    bytes_at(data, offset, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Returns the data of the first `RT_VERSION` resource.
+fn find_version_resource(data: &[u8]) -> Result<&[u8], PeError> {
+    if !data.starts_with(IMAGE_DOS_SIGNATURE) {
+        return Err(PeError::NotPe);
+    }
+    let nt_headers = u32_at(data, 0x3c).map_err(|_| PeError::NotPe)? as usize;
+    if bytes_at(data, nt_headers, 4) != Ok(IMAGE_NT_SIGNATURE) {
+        return Err(PeError::NotPe);
+    }
+
+    let file_header = nt_headers + 4;
+    let number_of_sections = u16_at(data, file_header + 2)? as usize;
+    let size_of_optional_header = u16_at(data, file_header + 16)? as usize;
+
+    let optional_header = file_header + 20;
+    let (rva_count_offset, directories_offset) = match u16_at(data, optional_header)? {
+        IMAGE_NT_OPTIONAL_HDR32_MAGIC => (92, 96),
+        IMAGE_NT_OPTIONAL_HDR64_MAGIC => (108, 112),
+        _ => return Err(PeError::NotPe),
+    };
+    let rva_count = u32_at(data, optional_header + rva_count_offset)? as usize;
+    if rva_count <= IMAGE_DIRECTORY_ENTRY_RESOURCE {
+        return Err(PeError::NoVersionInfo);
+    }
+    let resource_directory =
+        optional_header + directories_offset + IMAGE_DIRECTORY_ENTRY_RESOURCE * 8;
+    let resource_rva = u32_at(data, resource_directory)?;
+    if resource_rva == 0 {
+        return Err(PeError::NoVersionInfo);
+    }
+
+    let sections = (0..number_of_sections)
+        .map(|i| optional_header + size_of_optional_header + i * 40)
+        .map(|section| {
+            Ok((
+                u32_at(data, section + 8)?,
+                u32_at(data, section + 12)?,
+                u32_at(data, section + 16)?,
+                u32_at(data, section + 20)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // maps a relative virtual address to an offset in the file
+    let rva_to_offset = |rva: u32| {
+        sections
+            .iter()
+            .find(|(virtual_size, virtual_address, raw_size, _)| {
+                rva >= *virtual_address && rva - virtual_address < (*virtual_size).max(*raw_size)
+            })
+            .and_then(|(_, virtual_address, _, raw_pointer)| {
+                (rva - virtual_address).checked_add(*raw_pointer)
+            })
+            .map(|offset| offset as usize)
+            .ok_or(PeError::Malformed)
+    };
+
+    let resources = data
+        .get(rva_to_offset(resource_rva)?..)
+        .ok_or(PeError::Malformed)?;
+
+    // the resource tree has 3 levels: type, name and language
+    let names = find_resource_entry(resources, 0, Some(RT_VERSION))?;
+    let languages = find_resource_entry(resources, directory_offset(names)?, None)?;
+    let data_entry = find_resource_entry(resources, directory_offset(languages)?, None)?;
+    if data_entry & 0x8000_0000 != 0 {
+        return Err(PeError::Malformed);
+    }
+
+    let data_entry = data_entry as usize;
+    let offset = rva_to_offset(u32_at(resources, data_entry)?)?;
+    let size = u32_at(resources, data_entry + 4)? as usize;
+    bytes_at(data, offset, size)
+}
+
+fn directory_offset(entry: u32) -> Result<usize, PeError> {
+    if entry & 0x8000_0000 == 0 {
+        return Err(PeError::Malformed);
+    }
+    Ok((entry & 0x7fff_ffff) as usize)
+}
+
+/// Returns the `OffsetToData` of the entry with the integer `id`, or of the first entry if `id` is `None`.
+fn find_resource_entry(
+    resources: &[u8],
+    directory: usize,
+    id: Option<u32>,
+) -> Result<u32, PeError> {
+    let named = u16_at(resources, directory + 12)? as usize;
+    let ids = u16_at(resources, directory + 14)? as usize;
+    for i in 0..named + ids {
+        let entry = directory + 16 + i * 8;
+        let name = u32_at(resources, entry)?;
+        if id.is_none_or(|id| name == id) {
+            return u32_at(resources, entry + 4);
+        }
+    }
+    Err(PeError::NoVersionInfo)
+}
+
+/// A node of the `VS_VERSIONINFO` tree, like `StringFileInfo` or a `String`.
+struct Block<'a> {
+    key: String,
+    /// `wType`, `1` for text values.
+    value_type: u16,
+    value: &'a [u8],
+    children: &'a [u8],
+}
+
+/// Parses the block at the start of `data`, which must be 4-byte aligned, and returns it
+/// with the aligned length of the block.
+fn parse_block(data: &[u8]) -> Result<(Block<'_>, usize), PeError> {
+    let length = (u16_at(data, 0)? as usize).min(data.len());
+    let value_length = u16_at(data, 2)? as usize;
+    let value_type = u16_at(data, 4)?;
+    let data = &data[..length];
+
+    let mut key = Vec::new();
+    let mut offset = 6;
+    loop {
+        let c = u16_at(data, offset)?;
+        offset += 2;
+        if c == 0 {
+            break;
+        }
+        key.push(c);
+    }
+    let key = String::from_utf16_lossy(&key);
+
+    // text values are measured in characters
+    let value_size = if value_type == 1 {
+        value_length * 2
+    } else {
+        value_length
+    };
+    let value_start = align4(offset).min(data.len());
+    let value = &data[value_start..(value_start + value_size).min(data.len())];
+    let children = &data[align4(value_start + value_size).min(data.len())..];
+
+    Ok((
+        Block {
+            key,
+            value_type,
+            value,
+            children,
+        },
+        align4(length.max(1)),
+    ))
+}
+
+/// Iterates over the blocks in `data`, stopping at the first malformed block.
+fn blocks(mut data: &[u8]) -> impl Iterator<Item = Block<'_>> {
+    core::iter::from_fn(move || {
+        let (block, length) = parse_block(data).ok()?;
+        data = data.get(length..).unwrap_or_default();
+        Some(block)
+    })
+}
+
+fn text_value(block: &Block) -> String {
+    let text = block
+        .value
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&text)
+}
+
+fn parse_version_info(data: &[u8]) -> Result<VersionInfo, PeError> {
+    let (root, _) = parse_block(data)?;
+    if root.key != "VS_VERSION_INFO" {
+        return Err(PeError::Malformed);
+    }
+
+    let mut info = VersionInfo::default();
+
+    if u32_at(root.value, 0).ok() == Some(VS_FFI_SIGNATURE) {
+        let version = |offset| -> Result<[u16; 4], PeError> {
+            let ms = u32_at(root.value, offset)?;
+            let ls = u32_at(root.value, offset + 4)?;
+            Ok([(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16])
+        };
+        info.fixed = Some(FixedFileInfo {
+            file_version: version(8)?,
+            product_version: version(16)?,
+            file_flags: u32_at(root.value, 28)?,
+            file_os: u32_at(root.value, 32)?,
+            file_type: u32_at(root.value, 36)?,
+        });
+    }
+
+    let strings = blocks(root.children)
+        .filter(|block| block.key == "StringFileInfo")
+        .flat_map(|block| blocks(block.children))
+        .flat_map(|table| blocks(table.children));
+    for string in strings {
+        let value = if string.value_type == 1 || !string.value.is_empty() {
+            text_value(&string)
+        } else {
+            continue;
+        };
+        match string.key.as_str() {
+            "FileVersion" if info.file_version.is_none() => info.file_version = Some(value),
+            "ProductVersion" if info.product_version.is_none() => {
+                info.product_version = Some(value)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // generated with `llvm-rc -no-cpp /FO app.res app.rc`
+    // and `rust-lld -flavor link /NOENTRY /DLL /MACHINE:X86 /OUT:app-x86.dll app.res`
+    const APP_X86: &[u8] = include_bytes!("../testdata/app-x86.dll");
+    // same as above with `system.rc` and `/MACHINE:X64`
+    const SYSTEM_X64: &[u8] = include_bytes!("../testdata/system-x64.dll");
+
+    #[test]
+    fn pe32() {
+        let info = read_version_info(APP_X86).unwrap();
+        let fixed = info.fixed.unwrap();
+        assert_eq!(fixed.file_version, [1, 2, 3, 4]);
+        assert_eq!(fixed.product_version, [1, 2, 3, 0]);
+        assert_eq!(fixed.file_type, 1);
+        assert_eq!(info.file_version.as_deref(), Some("1.2.3.4"));
+        assert_eq!(info.product_version.as_deref(), Some("1.2.3-beta.1"));
+    }
+
+    #[test]
+    fn pe32_plus() {
+        let info = read_version_info(SYSTEM_X64).unwrap();
+        assert_eq!(info.fixed.unwrap().file_version, [10, 0, 19041, 1]);
+        assert_eq!(
+            info.file_version.as_deref(),
+            Some("10.0.19041.1 (WinBuild.160101.0800)")
+        );
+        assert_eq!(info.product_version.as_deref(), Some("10.0.19041.1"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(read_version_info(b""), Err(PeError::NotPe));
+        assert_eq!(read_version_info(b"MZ"), Err(PeError::NotPe));
+        assert_eq!(read_version_info(&APP_X86[..0x40]), Err(PeError::NotPe));
+
+        // every truncation must fail without panicking
+        for len in 0..APP_X86.len() {
+            let _ = read_version_info(&APP_X86[..len]);
+        }
+
+        // no resource directory
+        let mut data = APP_X86.to_vec();
+        let nt_headers = u32_at(&data, 0x3c).unwrap() as usize;
+        let resource_directory = nt_headers + 24 + 96 + IMAGE_DIRECTORY_ENTRY_RESOURCE * 8;
+        data[resource_directory..resource_directory + 8].fill(0);
+        assert_eq!(read_version_info(&data), Err(PeError::NoVersionInfo));
+    }
+}
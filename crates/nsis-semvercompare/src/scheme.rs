@@ -0,0 +1,428 @@
+//! Version schemes selectable by name in `VersionCompareWith`.
+//!
+//! A scheme only parses versions into something ordered, comparing them and handling
+//! invalid versions is shared by all schemes.
+
+use alloc::{string::String, vec::Vec};
+use core::cmp::Ordering;
+
+use semver::{Prerelease, Version};
+
+/// A way to parse versions into ordered values.
+pub trait VersionScheme {
+    type Version: Ord;
+
+    /// Parses a version, returns `None` if it is invalid in this scheme.
+    fn parse(version: &str) -> Option<Self::Version>;
+}
+
+/// Semantic versions, `1.2.3-beta.1+abc`.
+pub struct Semver;
+
+impl VersionScheme for Semver {
+    type Version = Version;
+
+    fn parse(version: &str) -> Option<Self::Version> {
+        Version::parse(version).ok()
+    }
+}
+
+/// Dotted numeric versions with any number of components, `10.0.19041.1`, see [`NumericVersion`].
+pub struct DottedNumeric;
+
+impl VersionScheme for DottedNumeric {
+    type Version = NumericVersion;
+
+    fn parse(version: &str) -> Option<Self::Version> {
+        NumericVersion::parse(version)
+    }
+}
+
+/// Calendar versions like `2024.10.3`, `24.04` or `2024.10.3-beta.1` and date stamps like `20240101`.
+///
+/// Two-digit years are in the 2000s and a leading `YYYYMMDD` component is split into year, month and day,
+/// so `24.04`, `2024.4` and `20240400` are equal. An optional modifier after `-` is compared like
+/// a semantic version pre-release.
+pub struct CalVer;
+
+impl VersionScheme for CalVer {
+    type Version = NumericVersion;
+
+    fn parse(version: &str) -> Option<Self::Version> {
+        let (core, modifier) = match version.split_once('-') {
+            Some((_, "")) => return None,
+            Some((core, modifier)) => (core, Prerelease::new(modifier).ok()?),
+            None => (version, Prerelease::EMPTY),
+        };
+
+        let mut components = Vec::new();
+        for (i, component) in core.split('.').enumerate() {
+            let value = parse_digits(component)?;
+            match (i, component.len()) {
+                (0, 8) => components.extend([value / 10000, value / 100 % 100, value % 100]),
+                (0, 1 | 2) => components.push(value + 2000),
+                _ => components.push(value),
+            }
+        }
+
+        Some(NumericVersion {
+            components,
+            pre: modifier,
+        })
+    }
+}
+
+/// Python package versions as specified by PEP 440, like `1!2.0.post1`, `1.0rc1`, `1.0.dev3` or `1.0+ubuntu.1`,
+/// including the normalized spellings like `1.0-alpha1` or `v1.0-1`.
+pub struct Pep440;
+
+impl VersionScheme for Pep440 {
+    type Version = Pep440Version;
+
+    fn parse(version: &str) -> Option<Self::Version> {
+        Pep440Version::parse(version)
+    }
+}
+
+/// Parses a component made of ASCII digits only.
+fn parse_digits(component: &str) -> Option<u32> {
+    if component.is_empty() || !component.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    component.parse().ok()
+}
+
+/// A dotted numeric version, or a semantic version reduced to its numeric components and pre-release.
+///
+/// Missing components are `0` so `1.2` is equal to `1.2.0.0`, and when the components are equal
+/// a version with a pre-release is older than one without.
+#[derive(Debug)]
+pub struct NumericVersion {
+    pub components: Vec<u32>,
+    pub pre: Prerelease,
+}
+
+impl NumericVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        let components = version
+            .split('.')
+            .map(parse_digits)
+            .collect::<Option<Vec<_>>>();
+        if let Some(components) = components {
+            return Some(Self {
+                components,
+                pre: Prerelease::EMPTY,
+            });
+        }
+
+        let version = Version::parse(version).ok()?;
+        let components = [version.major, version.minor, version.patch]
+            .into_iter()
+            .map(|c| u32::try_from(c).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            components,
+            pre: version.pre,
+        })
+    }
+
+    fn component(&self, i: usize) -> u32 {
+        self.components.get(i).copied().unwrap_or(0)
+    }
+}
+
+impl Ord for NumericVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.components.len().max(other.components.len());
+        (0..len)
+            .map(|i| self.component(i).cmp(&other.component(i)))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| self.pre.cmp(&other.pre))
+    }
+}
+
+impl PartialOrd for NumericVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for NumericVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for NumericVersion {}
+
+/// Orders `X.Y.devN` before the pre-releases of `X.Y`, and releases after them.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pep440Pre {
+    DevRelease,
+    /// `a`, `b` or `rc` as `0`, `1` or `2`, and the pre-release number.
+    Pre(u8, u64),
+    Release,
+}
+
+/// Orders development releases before the same version without a development release.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pep440Dev {
+    Dev(u64),
+    None,
+}
+
+/// Alphanumeric local version segments are older than numeric ones.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pep440Local {
+    Alphanumeric(String),
+    Numeric(u64),
+}
+
+/// The fields are ordered so the derived `Ord` follows PEP 440.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pep440Version {
+    epoch: u64,
+    /// Without trailing zeros, `1.0` and `1` are equal.
+    release: Vec<u64>,
+    pre: Pep440Pre,
+    post: Option<u64>,
+    dev: Pep440Dev,
+    local: Option<Vec<Pep440Local>>,
+}
+
+/// Removes `keyword` from the start of `s`, optionally preceded by a `.`, `-` or `_` separator.
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    s.strip_prefix(keyword).or_else(|| {
+        s.strip_prefix(['.', '-', '_'])
+            .and_then(|s| s.strip_prefix(keyword))
+    })
+}
+
+/// Takes the digits at the start of `s`.
+fn take_number(s: &str) -> Option<(u64, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+/// Takes the optional number following a pre-release, post-release or development release keyword,
+/// which is `0` when missing.
+fn take_implicit_number(s: &str) -> Option<(u64, &str)> {
+    match take_number(s.strip_prefix(['.', '-', '_']).unwrap_or(s)) {
+        Some(number) => Some(number),
+        None if s.starts_with(|c: char| c.is_ascii_digit()) => None,
+        None => Some((0, s)),
+    }
+}
+
+impl Pep440Version {
+    fn parse(version: &str) -> Option<Self> {
+        let version = version.trim().to_lowercase();
+        let version = version.strip_prefix('v').unwrap_or(&version);
+
+        let (version, local) = match version.split_once('+') {
+            Some((version, local)) => (version, Some(local)),
+            None => (version, None),
+        };
+
+        let (epoch, mut rest) = match version.split_once('!') {
+            Some((epoch, rest)) => (u64::from(parse_digits(epoch)?), rest),
+            None => (0, version),
+        };
+
+        let mut release = Vec::new();
+        loop {
+            let (number, r) = take_number(rest)?;
+            release.push(number);
+            rest = r;
+            match rest.strip_prefix('.') {
+                Some(r) if r.starts_with(|c: char| c.is_ascii_digit()) => rest = r,
+                _ => break,
+            }
+        }
+        while release.last() == Some(&0) {
+            release.pop();
+        }
+
+        let mut pre = None;
+        // longer spellings first so `alpha` isn't read as `a` followed by `lpha`
+        for (keyword, phase) in [
+            ("alpha", 0),
+            ("a", 0),
+            ("beta", 1),
+            ("b", 1),
+            ("preview", 2),
+            ("pre", 2),
+            ("rc", 2),
+            ("c", 2),
+        ] {
+            if let Some(r) = strip_keyword(rest, keyword) {
+                let (number, r) = take_implicit_number(r)?;
+                pre = Some((phase, number));
+                rest = r;
+                break;
+            }
+        }
+
+        let mut post = None;
+        if let Some((number, r)) = rest.strip_prefix('-').and_then(take_number) {
+            // implicit post release, `1.0-1`
+            post = Some(number);
+            rest = r;
+        } else {
+            for keyword in ["post", "rev", "r"] {
+                if let Some(r) = strip_keyword(rest, keyword) {
+                    let (number, r) = take_implicit_number(r)?;
+                    post = Some(number);
+                    rest = r;
+                    break;
+                }
+            }
+        }
+
+        let mut dev = None;
+        if let Some(r) = strip_keyword(rest, "dev") {
+            let (number, r) = take_implicit_number(r)?;
+            dev = Some(number);
+            rest = r;
+        }
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        let local = match local {
+            Some(local) => Some(
+                local
+                    .split(['.', '-', '_'])
+                    .map(|segment| match take_number(segment) {
+                        Some((number, "")) => Some(Pep440Local::Numeric(number)),
+                        _ if !segment.is_empty()
+                            && segment.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+                        {
+                            Some(Pep440Local::Alphanumeric(segment.into()))
+                        }
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+
+        let pre = match (pre, post, dev) {
+            (Some((phase, number)), _, _) => Pep440Pre::Pre(phase, number),
+            (None, None, Some(_)) => Pep440Pre::DevRelease,
+            (None, _, _) => Pep440Pre::Release,
+        };
+
+        Some(Self {
+            epoch,
+            release,
+            pre,
+            post,
+            dev: dev.map_or(Pep440Dev::None, Pep440Dev::Dev),
+            local,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmp<S: VersionScheme>(v1: &str, v2: &str) -> Ordering {
+        S::parse(v1)
+            .unwrap_or_else(|| panic!("invalid {v1}"))
+            .cmp(&S::parse(v2).unwrap_or_else(|| panic!("invalid {v2}")))
+    }
+
+    #[test]
+    fn calver() {
+        for (v1, v2, ordering) in [
+            ("2024.10.3", "24.04", Ordering::Greater),
+            ("24.04", "2024.4", Ordering::Equal),
+            ("24.04", "20240400", Ordering::Equal),
+            ("2024.10.3", "2024.10.3-beta.1", Ordering::Greater),
+            ("2024.10.3-beta.2", "2024.10.3-beta.1", Ordering::Greater),
+            ("20240101", "2023.12.31", Ordering::Greater),
+            ("20240101.2", "20240101.10", Ordering::Less),
+            ("2024.10", "2024.9.30", Ordering::Greater),
+            ("99.1", "2098.12", Ordering::Greater),
+        ] {
+            assert_eq!(cmp::<CalVer>(v1, v2), ordering, "({v1}, {v2})");
+        }
+
+        for version in [
+            "",
+            "2024.a",
+            "2024..1",
+            "v2024.1",
+            "2024.1-",
+            "2024.1-beta..1",
+        ] {
+            assert!(CalVer::parse(version).is_none(), "{version}");
+        }
+    }
+
+    #[test]
+    fn pep440() {
+        // in increasing order, from the examples of PEP 440
+        let versions = [
+            "1.0.dev456",
+            "1.0a1",
+            "1.0a2.dev456",
+            "1.0a12.dev456",
+            "1.0a12",
+            "1.0b1.dev456",
+            "1.0b2",
+            "1.0b2.post345.dev456",
+            "1.0b2.post345",
+            "1.0rc1.dev456",
+            "1.0rc1",
+            "1.0",
+            "1.0+abc.5",
+            "1.0+abc.7",
+            "1.0+5",
+            "1.0.post456.dev34",
+            "1.0.post456",
+            "1.0.15",
+            "1.1.dev1",
+            "1!0.1",
+        ];
+        for pair in versions.windows(2) {
+            assert_eq!(
+                cmp::<Pep440>(pair[0], pair[1]),
+                Ordering::Less,
+                "({}, {})",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        for (v1, v2) in [
+            ("1.0", "1.0.0"),
+            ("1.0", "v1.0"),
+            ("1.0a1", "1.0-alpha.1"),
+            ("1.0a0", "1.0a"),
+            ("1.0b2", "1.0_BETA2"),
+            ("1.0rc1", "1.0c1"),
+            ("1.0rc1", "1.0-preview-1"),
+            ("1.0.post1", "1.0-1"),
+            ("1.0.post1", "1.0rev1"),
+            ("1.0.post0", "1.0.post"),
+            ("1.0.dev0", "1.0-dev"),
+            ("0!1.0", "1.0"),
+            ("1.0+ubuntu-1", "1.0+ubuntu.1"),
+        ] {
+            assert_eq!(cmp::<Pep440>(v1, v2), Ordering::Equal, "({v1}, {v2})");
+        }
+
+        for version in [
+            "", "1.0x1", "1.0a1a2", "1.0+", "1.0+a..b", "a1.0", "1.0.", "1!",
+        ] {
+            assert!(Pep440::parse(version).is_none(), "{version}");
+        }
+    }
+}
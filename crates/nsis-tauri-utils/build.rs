@@ -2,7 +2,9 @@ use std::io::Write;
 
 fn main() {
     combine_plugins_and_write_to_out_dir();
-    if std::env::var("CARGO_FEATURE_TEST").as_deref() != Ok("1") {
+    if std::env::var("CARGO_FEATURE_TEST").as_deref() != Ok("1")
+        && std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc")
+    {
         println!("cargo::rustc-link-arg=/ENTRY:DllMain")
     }
 }
@@ -12,28 +14,48 @@ fn main() {
 ///
 /// Plugins are combined this way because it saves a few kilobytes in the generated DLL
 /// than the making nsis-tauri-utils depend on other plugins and re-export the DLLs
+///
+/// Each plugin is wrapped in its own module so their imports and private helpers don't clash,
+/// and modules declared by a plugin are loaded from the plugin's source directory.
 fn combine_plugins_and_write_to_out_dir() {
     let out_dir = std::env::var("OUT_DIR").unwrap();
     let path = format!("{out_dir}/combined_libs.rs");
     let mut file = std::fs::File::create(path).unwrap();
-    for plugin in [
-        include_str!("../nsis-semvercompare/src/lib.rs"),
-        include_str!("../nsis-process/src/lib.rs"),
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    for (name, dir, plugin) in [
+        (
+            "nsis_semvercompare",
+            "nsis-semvercompare",
+            include_str!("../nsis-semvercompare/src/lib.rs"),
+        ),
+        (
+            "nsis_process",
+            "nsis-process",
+            include_str!("../nsis-process/src/lib.rs"),
+        ),
     ] {
         let lines = plugin
             .lines()
             .filter(|l| {
                 // remove lines that should only be specified once
                 // either for compilation or for clippy
-                !(l.contains("#![no_std]")
-                    || l.contains("nsis_plugin!();")
-                    || l.contains("use nsis_plugin_api::*;"))
+                !(l.contains("#![no_std]") || l.contains("nsis_plugin!();"))
             })
             .take_while(|l| !l.contains("mod tests {"))
-            .collect::<Vec<&str>>();
+            .map(|l| {
+                // point `mod name;` declarations to the plugin's source directory
+                match l.strip_prefix("mod ").and_then(|l| l.strip_suffix(';')) {
+                    Some(module) => {
+                        let module_path = format!("{manifest_dir}/../{dir}/src/{module}.rs");
+                        format!("#[path = {module_path:?}]\n{l}")
+                    }
+                    None => l.to_string(),
+                }
+            })
+            .collect::<Vec<String>>();
 
         // skip last line which should be #[cfg(test)]
         let content = lines[..lines.len() - 1].join("\n");
-        file.write_all(content.as_bytes()).unwrap();
+        writeln!(file, "mod {name} {{\n{content}\n}}").unwrap();
     }
 }
@@ -1,5 +1,7 @@
 #![no_std]
 
+extern crate alloc;
+
 use nsis_plugin_api::*;
 
 nsis_plugin!();